@@ -0,0 +1,968 @@
+use crate::builder_dir::return_code::{Code, Return};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WordSize {
+    Byte,
+    Word,
+    Dword,
+    Qword,
+}
+
+impl WordSize {
+    pub fn from_bytes(bytes: u32) -> Self {
+        match bytes {
+            1 => WordSize::Byte,
+            2 => WordSize::Word,
+            4 => WordSize::Dword,
+            8 => WordSize::Qword,
+            _ => unreachable!("no {} byte word", bytes),
+        }
+    }
+    pub fn bytes(self) -> u32 {
+        match self {
+            WordSize::Byte => 1,
+            WordSize::Word => 2,
+            WordSize::Dword => 4,
+            WordSize::Qword => 8,
+        }
+    }
+    pub fn name(self) -> &'static str {
+        match self {
+            WordSize::Byte => "byte",
+            WordSize::Word => "word",
+            WordSize::Dword => "dword",
+            WordSize::Qword => "qword",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Register {
+    Rax,
+    Rbx,
+    Rcx,
+    Rdx,
+    Rsi,
+    Rdi,
+    Rbp,
+    Rsp,
+    R8,
+    R9,
+    R10,
+    R11,
+    R12,
+    R13,
+    R14,
+    R15,
+    // SSE registers, they only ever hold a double here
+    Xmm0,
+    Xmm1,
+    Xmm2,
+    Xmm3,
+    Xmm4,
+    Xmm5,
+    Xmm6,
+    Xmm7,
+}
+
+// a general purpose register at one of its widths, eax is Rax at Dword
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Reg {
+    pub register: Register,
+    pub size: WordSize,
+}
+
+impl Reg {
+    pub const fn new(register: Register, size: WordSize) -> Self {
+        Self { register, size }
+    }
+    pub fn with_size(self, size: WordSize) -> Self {
+        Self::new(self.register, size)
+    }
+    pub fn is_xmm(self) -> bool {
+        matches!(
+            self.register,
+            Register::Xmm0
+                | Register::Xmm1
+                | Register::Xmm2
+                | Register::Xmm3
+                | Register::Xmm4
+                | Register::Xmm5
+                | Register::Xmm6
+                | Register::Xmm7
+        )
+    }
+    pub fn name(self) -> String {
+        let legacy = |name: &str, ends_in_x: bool| -> String {
+            let x = if ends_in_x { "x" } else { "" };
+            match self.size {
+                WordSize::Byte => format!("{}l", name),
+                WordSize::Word => format!("{}{}", name, x),
+                WordSize::Dword => format!("e{}{}", name, x),
+                WordSize::Qword => format!("r{}{}", name, x),
+            }
+        };
+        let numbered = |number: u8| -> String {
+            match self.size {
+                WordSize::Byte => format!("r{}b", number),
+                WordSize::Word => format!("r{}w", number),
+                WordSize::Dword => format!("r{}d", number),
+                WordSize::Qword => format!("r{}", number),
+            }
+        };
+        match self.register {
+            Register::Rax => legacy("a", true),
+            Register::Rbx => legacy("b", true),
+            Register::Rcx => legacy("c", true),
+            Register::Rdx => legacy("d", true),
+            Register::Rsi => legacy("si", false),
+            Register::Rdi => legacy("di", false),
+            Register::Rbp => legacy("bp", false),
+            Register::Rsp => legacy("sp", false),
+            Register::R8 => numbered(8),
+            Register::R9 => numbered(9),
+            Register::R10 => numbered(10),
+            Register::R11 => numbered(11),
+            Register::R12 => numbered(12),
+            Register::R13 => numbered(13),
+            Register::R14 => numbered(14),
+            Register::R15 => numbered(15),
+            Register::Xmm0 => "xmm0".to_string(),
+            Register::Xmm1 => "xmm1".to_string(),
+            Register::Xmm2 => "xmm2".to_string(),
+            Register::Xmm3 => "xmm3".to_string(),
+            Register::Xmm4 => "xmm4".to_string(),
+            Register::Xmm5 => "xmm5".to_string(),
+            Register::Xmm6 => "xmm6".to_string(),
+            Register::Xmm7 => "xmm7".to_string(),
+        }
+    }
+}
+
+pub const RAX: Reg = Reg::new(Register::Rax, WordSize::Qword);
+pub const EAX: Reg = Reg::new(Register::Rax, WordSize::Dword);
+#[allow(dead_code)]
+pub const RBX: Reg = Reg::new(Register::Rbx, WordSize::Qword);
+pub const RCX: Reg = Reg::new(Register::Rcx, WordSize::Qword);
+pub const ECX: Reg = Reg::new(Register::Rcx, WordSize::Dword);
+pub const RDX: Reg = Reg::new(Register::Rdx, WordSize::Qword);
+pub const EDX: Reg = Reg::new(Register::Rdx, WordSize::Dword);
+pub const RSI: Reg = Reg::new(Register::Rsi, WordSize::Qword);
+pub const ESI: Reg = Reg::new(Register::Rsi, WordSize::Dword);
+pub const RDI: Reg = Reg::new(Register::Rdi, WordSize::Qword);
+pub const EDI: Reg = Reg::new(Register::Rdi, WordSize::Dword);
+pub const DIL: Reg = Reg::new(Register::Rdi, WordSize::Byte);
+pub const RBP: Reg = Reg::new(Register::Rbp, WordSize::Qword);
+pub const RSP: Reg = Reg::new(Register::Rsp, WordSize::Qword);
+pub const R8: Reg = Reg::new(Register::R8, WordSize::Qword);
+pub const R8D: Reg = Reg::new(Register::R8, WordSize::Dword);
+pub const R9: Reg = Reg::new(Register::R9, WordSize::Qword);
+pub const R9D: Reg = Reg::new(Register::R9, WordSize::Dword);
+pub const R10: Reg = Reg::new(Register::R10, WordSize::Qword);
+pub const CL: Reg = Reg::new(Register::Rcx, WordSize::Byte);
+pub const XMM0: Reg = Reg::new(Register::Xmm0, WordSize::Qword);
+pub const XMM1: Reg = Reg::new(Register::Xmm1, WordSize::Qword);
+pub const XMM2: Reg = Reg::new(Register::Xmm2, WordSize::Qword);
+pub const XMM3: Reg = Reg::new(Register::Xmm3, WordSize::Qword);
+pub const XMM4: Reg = Reg::new(Register::Xmm4, WordSize::Qword);
+pub const XMM5: Reg = Reg::new(Register::Xmm5, WordSize::Qword);
+pub const XMM6: Reg = Reg::new(Register::Xmm6, WordSize::Qword);
+pub const XMM7: Reg = Reg::new(Register::Xmm7, WordSize::Qword);
+
+// `size [base + offset]`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Memory {
+    pub size: WordSize,
+    pub base: Reg,
+    pub offset: i32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Operand {
+    Register(Reg),
+    Immediate(i128),
+    Memory(Memory),
+    // the address of a label, like a string literal
+    Label(String),
+    // the value stored at a label, like a float constant
+    Data { size: WordSize, label: String },
+}
+
+impl Operand {
+    // None for immediates and labels, they take the size of the other operand
+    pub fn size(&self) -> Option<WordSize> {
+        match self {
+            Operand::Register(register) => Some(register.size),
+            Operand::Memory(memory) => Some(memory.size),
+            Operand::Data { size, .. } => Some(*size),
+            Operand::Immediate(_) | Operand::Label(_) => None,
+        }
+    }
+    pub fn is_memory(&self) -> bool {
+        matches!(self, Operand::Memory(_) | Operand::Data { .. })
+    }
+}
+
+impl From<Reg> for Operand {
+    fn from(register: Reg) -> Self {
+        Operand::Register(register)
+    }
+}
+impl From<Memory> for Operand {
+    fn from(memory: Memory) -> Self {
+        Operand::Memory(memory)
+    }
+}
+
+pub fn imm(value: impl Into<i128>) -> Operand {
+    Operand::Immediate(value.into())
+}
+pub fn label(name: &str) -> Operand {
+    Operand::Label(name.to_string())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Opcode {
+    Mov,
+    // zero extends a byte into a dword register
+    Movzx,
+    Add,
+    Sub,
+    Cmp,
+    Neg,
+    Imul,
+    // sign extends eax into edx:eax, or rax into rdx:rax, ahead of idiv
+    Cdq,
+    Cqo,
+    Idiv,
+    And,
+    Or,
+    // writes 1 to a byte when the condition holds and 0 otherwise
+    Set(Condition),
+    Push,
+    Pop,
+    Leave,
+    Ret,
+    #[allow(dead_code)]
+    Syscall,
+    // scalar double arithmetic, the destination is an xmm register
+    Movsd,
+    Addsd,
+    Subsd,
+    Mulsd,
+    Divsd,
+    // compares like cmp, but sets the flags of an unsigned comparison
+    Ucomisd,
+    // int to double, and double to int truncating towards zero
+    Cvtsi2sd,
+    Cvttsd2si,
+}
+
+impl Opcode {
+    pub fn name(self) -> &'static str {
+        match self {
+            Opcode::Mov => "mov",
+            Opcode::Movzx => "movzx",
+            Opcode::Add => "add",
+            Opcode::Sub => "sub",
+            Opcode::Cmp => "cmp",
+            Opcode::Neg => "neg",
+            Opcode::Imul => "imul",
+            Opcode::Cdq => "cdq",
+            Opcode::Cqo => "cqo",
+            Opcode::Idiv => "idiv",
+            Opcode::And => "and",
+            Opcode::Or => "or",
+            Opcode::Set(condition) => match condition {
+                Condition::Equal => "sete",
+                Condition::NotEqual => "setne",
+                Condition::Less => "setl",
+                Condition::LessEqual => "setle",
+                Condition::Greater => "setg",
+                Condition::GreaterEqual => "setge",
+                Condition::Below => "setb",
+                Condition::BelowEqual => "setbe",
+                Condition::Above => "seta",
+                Condition::AboveEqual => "setae",
+                Condition::Parity => "setp",
+                Condition::NoParity => "setnp",
+            },
+            Opcode::Push => "push",
+            Opcode::Pop => "pop",
+            Opcode::Leave => "leave",
+            Opcode::Ret => "ret",
+            Opcode::Syscall => "syscall",
+            Opcode::Movsd => "movsd",
+            Opcode::Addsd => "addsd",
+            Opcode::Subsd => "subsd",
+            Opcode::Mulsd => "mulsd",
+            Opcode::Divsd => "divsd",
+            Opcode::Ucomisd => "ucomisd",
+            Opcode::Cvtsi2sd => "cvtsi2sd",
+            Opcode::Cvttsd2si => "cvttsd2si",
+        }
+    }
+    fn operand_count(self) -> usize {
+        match self {
+            Opcode::Mov
+            | Opcode::Movzx
+            | Opcode::Add
+            | Opcode::Sub
+            | Opcode::Cmp
+            | Opcode::Imul
+            | Opcode::And
+            | Opcode::Or
+            | Opcode::Movsd
+            | Opcode::Addsd
+            | Opcode::Subsd
+            | Opcode::Mulsd
+            | Opcode::Divsd
+            | Opcode::Ucomisd
+            | Opcode::Cvtsi2sd
+            | Opcode::Cvttsd2si => 2,
+            Opcode::Neg | Opcode::Idiv | Opcode::Set(_) | Opcode::Push | Opcode::Pop => 1,
+            Opcode::Cdq | Opcode::Cqo | Opcode::Leave | Opcode::Ret | Opcode::Syscall => 0,
+        }
+    }
+    // whether the destination has to be an xmm register
+    fn is_sse(self) -> bool {
+        matches!(
+            self,
+            Opcode::Addsd
+                | Opcode::Subsd
+                | Opcode::Mulsd
+                | Opcode::Divsd
+                | Opcode::Ucomisd
+                | Opcode::Cvtsi2sd
+        )
+    }
+}
+
+// the flags a conditional jump tests, signed comparisons of ints, unsigned ones of chars
+// and of the flags ucomisd leaves for doubles, and the parity flag it sets for NaN
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Condition {
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    Below,
+    BelowEqual,
+    Above,
+    AboveEqual,
+    Parity,
+    NoParity,
+}
+
+impl Condition {
+    // the jcc and setcc suffix
+    pub fn suffix(self) -> &'static str {
+        match self {
+            Condition::Equal => "e",
+            Condition::NotEqual => "ne",
+            Condition::Less => "l",
+            Condition::LessEqual => "le",
+            Condition::Greater => "g",
+            Condition::GreaterEqual => "ge",
+            Condition::Below => "b",
+            Condition::BelowEqual => "be",
+            Condition::Above => "a",
+            Condition::AboveEqual => "ae",
+            Condition::Parity => "p",
+            Condition::NoParity => "np",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Instr {
+    // operands in Intel order, destination first
+    Op(Opcode, Vec<Operand>),
+    // jmp when there is no condition
+    Jump(Option<Condition>, String),
+    Call(String),
+    Label(String),
+}
+
+impl Instr {
+    // catches instructions no assembler would accept, like a mov from eax to rbx
+    pub fn validate(&self) -> Result<(), String> {
+        let Instr::Op(op, operands) = self else {
+            return Ok(());
+        };
+        if operands.len() != op.operand_count() {
+            return Err(format!(
+                "{} takes {} operands, got {}",
+                op.name(),
+                op.operand_count(),
+                operands.len()
+            ));
+        }
+        let is_xmm = |operand: &Operand| matches!(operand, Operand::Register(register) if register.is_xmm());
+        let takes_xmm = op.is_sse() || matches!(op, Opcode::Movsd | Opcode::Cvttsd2si);
+        if !takes_xmm && operands.iter().any(is_xmm) {
+            return Err(format!("{} cannot take an xmm register", op.name()));
+        }
+        match operands.as_slice() {
+            [destination, _] if op.is_sse() && !is_xmm(destination) => {
+                return Err(format!("{} needs an xmm register destination", op.name()));
+            }
+            [destination, _]
+                if *op == Opcode::Cvttsd2si
+                    && (is_xmm(destination) || !matches!(destination, Operand::Register(_))) =>
+            {
+                return Err("cvttsd2si needs a general purpose register destination".to_string());
+            }
+            [Operand::Register(register)]
+                if register.size != WordSize::Qword && matches!(op, Opcode::Push | Opcode::Pop) =>
+            {
+                return Err(format!("{} needs a 64 bit register", op.name()));
+            }
+            [Operand::Immediate(_) | Operand::Label(_)] if *op == Opcode::Idiv => {
+                return Err("idiv needs a register or memory operand".to_string());
+            }
+            [operand] if matches!(op, Opcode::Set(_)) && operand.size() != Some(WordSize::Byte) => {
+                return Err(format!("{} needs a byte register or memory operand", op.name()));
+            }
+            [destination, source]
+                if *op == Opcode::Movzx
+                    && (!matches!(destination, Operand::Register(_)) || source.size() != Some(WordSize::Byte)) =>
+            {
+                return Err("movzx needs a register destination and a byte source".to_string());
+            }
+            [Operand::Memory(_), _] if *op == Opcode::Imul => {
+                return Err("imul needs a register destination".to_string());
+            }
+            [destination, source] => {
+                if matches!(destination, Operand::Immediate(_) | Operand::Label(_)) {
+                    return Err("the destination has to be a register or memory".to_string());
+                }
+                if destination.is_memory() && source.is_memory() {
+                    return Err("both operands are memory".to_string());
+                }
+                // conversions are the one place operands of different sizes meet
+                let converts = matches!(op, Opcode::Cvtsi2sd | Opcode::Cvttsd2si | Opcode::Movzx);
+                if let (Some(destination_size), Some(source_size), false) =
+                    (destination.size(), source.size(), converts)
+                {
+                    if destination_size != source_size {
+                        return Err(format!(
+                            "operand sizes differ, {} and {}",
+                            destination_size.name(),
+                            source_size.name()
+                        ));
+                    }
+                }
+                if let Operand::Label(_) = source {
+                    if !matches!(destination, Operand::Register(_)) {
+                        return Err("a label address can only be loaded into a register".to_string());
+                    }
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+// a function's instructions, kept until build() renders them
+#[derive(Debug, Clone, PartialEq)]
+pub struct Function {
+    pub name: String,
+    pub body: Vec<Instr>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TextItem {
+    Global(String),
+    Extern(String),
+    Function(Function),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dialect {
+    Nasm,
+    Gas,
+}
+
+impl Dialect {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "nasm" => Some(Dialect::Nasm),
+            "gas" => Some(Dialect::Gas),
+            _ => None,
+        }
+    }
+}
+
+// the sections, current function and stack frame every backend keeps track of
+pub struct BuilderState {
+    // the text section header, build() renders text_items after it
+    pub text: String,
+    pub text_items: Vec<TextItem>,
+    pub bss: String,
+    pub data: String,
+    // the function being built, None outside of open_function and close_function
+    pub function: Option<Function>,
+    pub local_variables: Vec<u32>,
+    pub local_variables_offset: Vec<u32>,
+    // bytes of locals below rbp, the frame is this rounded up to 16
+    pub local_offset: u32,
+    // bytes pushed below the frame, call pads rsp when this is not a multiple of 16
+    pub stack_offset: u32,
+    pub label_count: usize,
+    pub func_args_order: Vec<Reg>,
+    // where float arguments go, counted separately from the others
+    pub float_args_order: Vec<Reg>,
+    #[allow(dead_code)]
+    pub syscall_args_ordering: Vec<Reg>,
+}
+
+impl BuilderState {
+    pub fn new(text: &str, bss: &str, data: &str) -> Self {
+        Self {
+            text: text.to_string(),
+            text_items: vec![],
+            bss: bss.to_string(),
+            data: data.to_string(),
+            function: None,
+            local_variables: vec![],
+            local_variables_offset: vec![],
+            local_offset: 0,
+            stack_offset: 0,
+            label_count: 0,
+            syscall_args_ordering: vec![RAX, RDI, RSI, RDX, R10, R8, R9],
+            func_args_order: vec![EDI, ESI, EDX, ECX, R8D, R9D],
+            float_args_order: vec![XMM0, XMM1, XMM2, XMM3, XMM4, XMM5, XMM6, XMM7],
+        }
+    }
+}
+
+// lines up the operands of every instruction in one column
+pub fn align_instruction(op: &str, operands: &str) -> String {
+    if operands.is_empty() {
+        op.to_string()
+    } else if op.len() > 3 {
+        format!("{}\t\t\t{}", op, operands)
+    } else {
+        format!("{}\t\t\t\t{}", op, operands)
+    }
+}
+
+// everything the compiler needs from an assembler dialect, only the syntax hooks at the top
+// differ between backends, the rest is shared through BuilderState
+pub trait Backend {
+    fn new_program(start_function: &str) -> Self
+    where
+        Self: Sized;
+    fn state(&self) -> &BuilderState;
+    fn state_mut(&mut self) -> &mut BuilderState;
+
+    // instruction with its operands in Intel order, destination first
+    fn render_instruction(&self, op: Opcode, operands: &[Operand]) -> String;
+    fn global_directive(&self, name: &str) -> String;
+    fn extern_directive(&self, name: &str) -> String;
+    // a zero terminated string in the data section
+    fn string_directive(&self, label: &str, value: &str) -> String;
+    // a double in the data section, written out bit for bit
+    fn float_directive(&self, label: &str, value: f64) -> String;
+    // an unlabeled qword in the data section
+    fn quad_directive(&self, value: u64) -> String;
+    // the _start entry point build() appends
+    #[allow(dead_code)]
+    fn start_code(&self) -> &'static str;
+    // what a call to the function names, gas picks the plt for externs on its own
+    fn call_target(&self, function: &str) -> String {
+        function.to_string()
+    }
+
+    // jumps, calls and labels are written the same way in every dialect
+    fn render(&self, instr: &Instr) -> String {
+        match instr {
+            Instr::Op(op, operands) => format!("\t\t{}", self.render_instruction(*op, operands)),
+            Instr::Jump(None, target) => format!("\t\t{}", align_instruction("jmp", target)),
+            Instr::Jump(Some(condition), target) => format!(
+                "\t\t{}",
+                align_instruction(&format!("j{}", condition.suffix()), target)
+            ),
+            Instr::Call(function) => format!(
+                "\t\t{}",
+                align_instruction("call", &self.call_target(function))
+            ),
+            Instr::Label(label) => format!("\t{}:", label),
+        }
+    }
+    #[allow(dead_code)]
+    fn add_line_bss(&mut self, line: &str) {
+        self.state_mut().bss += &format!("\t{}\n", line)
+    }
+    fn add_line_data(&mut self, line: &str) {
+        self.state_mut().data += &format!("\t{}\n", line)
+    }
+    // appends to the current function, panics on instructions an assembler would reject
+    fn emit(&mut self, instr: Instr) {
+        if let Err(message) = instr.validate() {
+            panic!("invalid instruction {:?}: {}", instr, message);
+        }
+        self.state_mut()
+            .function
+            .as_mut()
+            .expect("instruction outside of a function")
+            .body
+            .push(instr);
+    }
+    fn instruction(&mut self, op: Opcode, operands: Vec<Operand>) {
+        self.emit(Instr::Op(op, operands))
+    }
+    #[allow(dead_code)]
+    fn set_func_args_order(&mut self, order: Vec<Reg>) -> Return {
+        if order.len() != self.state().func_args_order.len() {
+            Return::new(
+                format!(
+                    "Length of order is not equal to base order, expected {} size got {} instead",
+                    self.state().func_args_order.len(),
+                    order.len()
+                ),
+                Code::BadArguments,
+            )
+        } else {
+            self.state_mut().func_args_order = order;
+            Return::new("".to_string(), Code::Good)
+        }
+    }
+
+    fn open_function(&mut self, function_name: &str) -> Return {
+        if self.state().function.is_some() {
+            return Return::new(
+                "Cannot Create Function Within a Function".to_string(),
+                Code::FunctionWithinFunctionErr,
+            );
+        }
+        let state = self.state_mut();
+        state.stack_offset = 0;
+        state.function = Some(Function {
+            name: function_name.to_string(),
+            body: vec![],
+        });
+        // leave undoes the push, so it does not count towards the stack offset
+        self.instruction(Opcode::Push, vec![RBP.into()]);
+        self.mov(RBP, RSP);
+
+        Return::new("Everything Is Fine".to_string(), Code::Good)
+    }
+    fn close_function(&mut self) -> Return {
+        if self.state().function.is_none() {
+            return Return::new(
+                "Is not in a Function".to_string(),
+                Code::ClosingOfNonFunctionErr,
+            );
+        }
+        // rsp is 16 byte aligned right after `push rbp`, so a frame of a multiple of 16 keeps it that way
+        let frame_size = self.state().local_offset.div_ceil(16) * 16;
+        self.instruction(Opcode::Leave, vec![]);
+        self.instruction(Opcode::Ret, vec![]);
+        let state = self.state_mut();
+        let mut function = state.function.take().unwrap();
+        if frame_size > 0 {
+            // right after the prologue, the frame size is only known once every local is assigned
+            let sub = Instr::Op(Opcode::Sub, vec![RSP.into(), imm(frame_size)]);
+            function.body.insert(2, sub);
+        }
+        state.text_items.push(TextItem::Function(function));
+        state.local_offset = 0;
+        state.local_variables = vec![];
+        state.local_variables_offset = vec![];
+        Return::new("".to_string(), Code::Good)
+    }
+
+    // a stack slot in the current frame, returns the id local_operand takes
+    fn new_local(&mut self, size: WordSize) -> usize {
+        let state = self.state_mut();
+        // every local is aligned to its own size
+        let bytes = size.bytes();
+        let offset = (state.local_offset + bytes).div_ceil(bytes) * bytes;
+        state.local_offset = offset;
+        state.local_variables.push(bytes);
+        state.local_variables_offset.push(offset);
+        state.local_variables.len() - 1
+    }
+    #[allow(dead_code)]
+    fn local_word_assign(&mut self, size: WordSize, value: impl Into<Operand>) -> Return {
+        if self.state().function.is_none() {
+            return Return::new(
+                "cannot assign local variable not in function".to_string(),
+                Code::LocalVariableNotInFunction,
+            );
+        }
+        let id = self.new_local(size);
+        let local = self.local_operand(id);
+        self.mov(local, value);
+        Return::new("".to_string(), Code::Good)
+    }
+    #[allow(dead_code)]
+    fn new_local_word(&mut self, value: u16) -> Return {
+        self.local_word_assign(WordSize::Word, imm(value))
+    }
+    #[allow(dead_code)]
+    fn new_local_dword(&mut self, value: u32) -> Return {
+        self.local_word_assign(WordSize::Dword, imm(value))
+    }
+    #[allow(dead_code)]
+    fn new_local_qword(&mut self, value: u64) -> Return {
+        self.local_word_assign(WordSize::Qword, imm(value))
+    }
+    // where a local lives, e.g. `dword [rbp - 8]`
+    fn local_operand(&self, id: usize) -> Operand {
+        let state = self.state();
+        Operand::Memory(Memory {
+            size: WordSize::from_bytes(state.local_variables[id]),
+            base: RBP,
+            offset: -(state.local_variables_offset[id] as i32),
+        })
+    }
+    fn func_args_order(&self) -> Vec<Reg> {
+        self.state().func_args_order.clone()
+    }
+    fn float_args_order(&self) -> Vec<Reg> {
+        self.state().float_args_order.clone()
+    }
+
+    // the label points at the bytes, the length sits in the qword right before them
+    fn new_string_literal(&mut self, addr: &str, value: &str) {
+        let length = self.quad_directive(value.len() as u64);
+        self.add_line_data(&length);
+        let line = self.string_directive(addr, value);
+        self.add_line_data(&line);
+    }
+    fn new_float_literal(&mut self, addr: &str, value: f64) {
+        let line = self.float_directive(addr, value);
+        self.add_line_data(&line);
+    }
+    fn call_function(&mut self, function: &str, arguments: Vec<Operand>) {
+        for (register, argument) in self.func_args_order().into_iter().zip(arguments) {
+            self.mov(register, argument);
+        }
+        self.call(function);
+    }
+    fn extern_add(&mut self, function_or_address: &str) {
+        self.state_mut()
+            .text_items
+            .push(TextItem::Extern(function_or_address.to_string()))
+    }
+    fn global_add(&mut self, function_or_address: &str) {
+        self.state_mut()
+            .text_items
+            .push(TextItem::Global(function_or_address.to_string()))
+    }
+
+    fn pop(&mut self, register: Reg) {
+        self.state_mut().stack_offset -= 8;
+        self.instruction(Opcode::Pop, vec![register.into()])
+    }
+    fn push(&mut self, register: Reg) {
+        self.state_mut().stack_offset += 8;
+        self.instruction(Opcode::Push, vec![register.into()])
+    }
+    // the System V ABI wants rsp 16 byte aligned at every call
+    fn call(&mut self, function: &str) {
+        if !self.state().stack_offset.is_multiple_of(16) {
+            self.sub(RSP, imm(8));
+            self.emit(Instr::Call(function.to_string()));
+            self.add(RSP, imm(8));
+        } else {
+            self.emit(Instr::Call(function.to_string()))
+        }
+    }
+    // pads rsp so it is aligned again once `stack_arguments` qwords are pushed for a call,
+    // returns the padding that has to be released along with the arguments
+    fn align_stack_for_call(&mut self, stack_arguments: usize) -> u32 {
+        let pushed = self.state().stack_offset + 8 * stack_arguments as u32;
+        if stack_arguments > 0 && !pushed.is_multiple_of(16) {
+            self.reserve_stack(8);
+            8
+        } else {
+            0
+        }
+    }
+    fn reserve_stack(&mut self, bytes: u32) {
+        self.state_mut().stack_offset += bytes;
+        self.sub(RSP, imm(bytes));
+    }
+    fn release_stack(&mut self, bytes: u32) {
+        self.state_mut().stack_offset -= bytes;
+        self.add(RSP, imm(bytes));
+    }
+
+    fn mov(&mut self, destination: impl Into<Operand>, source: impl Into<Operand>) {
+        self.instruction(Opcode::Mov, vec![destination.into(), source.into()]);
+    }
+    fn add(&mut self, destination: impl Into<Operand>, source: impl Into<Operand>) {
+        self.instruction(Opcode::Add, vec![destination.into(), source.into()]);
+    }
+    fn sub(&mut self, destination: impl Into<Operand>, source: impl Into<Operand>) {
+        self.instruction(Opcode::Sub, vec![destination.into(), source.into()]);
+    }
+    fn cmp(&mut self, left: impl Into<Operand>, right: impl Into<Operand>) {
+        self.instruction(Opcode::Cmp, vec![left.into(), right.into()]);
+    }
+    fn neg(&mut self, operand: impl Into<Operand>) {
+        self.instruction(Opcode::Neg, vec![operand.into()]);
+    }
+    fn imul(&mut self, destination: impl Into<Operand>, source: impl Into<Operand>) {
+        self.instruction(Opcode::Imul, vec![destination.into(), source.into()]);
+    }
+    // signed division of edx:eax (rdx:rax for a qword divisor), quotient in eax and remainder in edx
+    fn idiv(&mut self, divisor: impl Into<Operand>) {
+        let divisor = divisor.into();
+        let extend = match divisor.size() {
+            Some(WordSize::Qword) => Opcode::Cqo,
+            _ => Opcode::Cdq,
+        };
+        self.instruction(extend, vec![]);
+        self.instruction(Opcode::Idiv, vec![divisor]);
+    }
+    fn set(&mut self, condition: Condition, destination: impl Into<Operand>) {
+        self.instruction(Opcode::Set(condition), vec![destination.into()]);
+    }
+    fn movzx(&mut self, destination: Reg, source: impl Into<Operand>) {
+        self.instruction(Opcode::Movzx, vec![destination.into(), source.into()]);
+    }
+    fn and(&mut self, destination: impl Into<Operand>, source: impl Into<Operand>) {
+        self.instruction(Opcode::And, vec![destination.into(), source.into()]);
+    }
+    fn or(&mut self, destination: impl Into<Operand>, source: impl Into<Operand>) {
+        self.instruction(Opcode::Or, vec![destination.into(), source.into()]);
+    }
+    fn movsd(&mut self, destination: impl Into<Operand>, source: impl Into<Operand>) {
+        self.instruction(Opcode::Movsd, vec![destination.into(), source.into()]);
+    }
+    // addsd, subsd, mulsd or divsd
+    fn float_op(&mut self, op: Opcode, destination: Reg, source: impl Into<Operand>) {
+        self.instruction(op, vec![destination.into(), source.into()]);
+    }
+    fn ucomisd(&mut self, left: Reg, right: impl Into<Operand>) {
+        self.instruction(Opcode::Ucomisd, vec![left.into(), right.into()]);
+    }
+    fn cvtsi2sd(&mut self, destination: Reg, source: impl Into<Operand>) {
+        self.instruction(Opcode::Cvtsi2sd, vec![destination.into(), source.into()]);
+    }
+    fn cvttsd2si(&mut self, destination: Reg, source: impl Into<Operand>) {
+        self.instruction(Opcode::Cvttsd2si, vec![destination.into(), source.into()]);
+    }
+    fn jmp(&mut self, label: &str) {
+        self.emit(Instr::Jump(None, label.to_string()))
+    }
+    fn jump_if(&mut self, condition: Condition, label: &str) {
+        self.emit(Instr::Jump(Some(condition), label.to_string()))
+    }
+    fn new_label(&mut self, name: &str) -> String {
+        let state = self.state_mut();
+        state.label_count += 1;
+        format!(".L{}_{}", name, state.label_count)
+    }
+    fn label(&mut self, label: &str) {
+        self.emit(Instr::Label(label.to_string()))
+    }
+
+    #[allow(dead_code)]
+    fn syscall(&mut self, arguments: Vec<Operand>) {
+        let registers = self.state().syscall_args_ordering.clone();
+        for (register, argument) in registers.into_iter().zip(arguments) {
+            self.mov(register, argument);
+        }
+        self.instruction(Opcode::Syscall, vec![]);
+    }
+
+    #[allow(dead_code)]
+    fn build(&self) -> String {
+        self.build_no_start() + self.start_code()
+    }
+    fn build_no_start(&self) -> String {
+        let state = self.state();
+        let mut text = state.text.clone();
+        for item in &state.text_items {
+            match item {
+                TextItem::Global(name) => text += &format!("\t{}\n", self.global_directive(name)),
+                TextItem::Extern(name) => text += &format!("\t{}\n", self.extern_directive(name)),
+                TextItem::Function(function) => {
+                    text += &format!("\t{}:\n", function.name);
+                    for instr in &function.body {
+                        text += &self.render(instr);
+                        text += "\n";
+                    }
+                    text += "\n";
+                }
+            }
+        }
+        state.data.to_owned() + &state.bss + &text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EBX: Reg = Reg::new(Register::Rbx, WordSize::Dword);
+
+    fn op(opcode: Opcode, operands: Vec<Operand>) -> Result<(), String> {
+        Instr::Op(opcode, operands).validate()
+    }
+    fn local(size: WordSize) -> Operand {
+        Operand::Memory(Memory {
+            size,
+            base: RBP,
+            offset: -8,
+        })
+    }
+    fn float(label: &str) -> Operand {
+        Operand::Data {
+            size: WordSize::Qword,
+            label: label.to_string(),
+        }
+    }
+
+    #[test]
+    fn accepts_well_formed_instructions() {
+        assert_eq!(op(Opcode::Mov, vec![EAX.into(), imm(5)]), Ok(()));
+        assert_eq!(op(Opcode::Mov, vec![RAX.into(), local(WordSize::Qword)]), Ok(()));
+        assert_eq!(op(Opcode::Mov, vec![RDI.into(), label("local_string_0")]), Ok(()));
+        assert_eq!(op(Opcode::Movzx, vec![EAX.into(), DIL.into()]), Ok(()));
+        assert_eq!(op(Opcode::Set(Condition::Equal), vec![CL.into()]), Ok(()));
+        assert_eq!(op(Opcode::Idiv, vec![local(WordSize::Dword)]), Ok(()));
+        assert_eq!(op(Opcode::Push, vec![RBX.into()]), Ok(()));
+        assert_eq!(op(Opcode::Cdq, vec![]), Ok(()));
+        assert_eq!(op(Opcode::Addsd, vec![XMM0.into(), float("local_float_0")]), Ok(()));
+        assert_eq!(op(Opcode::Movsd, vec![local(WordSize::Qword), XMM1.into()]), Ok(()));
+        // conversions are allowed to mix operand sizes
+        assert_eq!(op(Opcode::Cvtsi2sd, vec![XMM0.into(), EAX.into()]), Ok(()));
+        assert_eq!(op(Opcode::Cvttsd2si, vec![EAX.into(), XMM0.into()]), Ok(()));
+    }
+
+    #[test]
+    fn rejects_operand_mismatches() {
+        assert!(op(Opcode::Mov, vec![EAX.into()]).is_err());
+        assert!(op(Opcode::Mov, vec![EAX.into(), RBX.into()]).is_err());
+        assert!(op(Opcode::Add, vec![local(WordSize::Dword), local(WordSize::Dword)]).is_err());
+        assert!(op(Opcode::Mov, vec![local(WordSize::Qword), float("local_float_0")]).is_err());
+        assert!(op(Opcode::Mov, vec![imm(5), EAX.into()]).is_err());
+        assert!(op(Opcode::Mov, vec![local(WordSize::Qword), label("local_string_0")]).is_err());
+        assert!(op(Opcode::Imul, vec![local(WordSize::Dword), EAX.into()]).is_err());
+        assert!(op(Opcode::Idiv, vec![imm(2)]).is_err());
+        assert!(op(Opcode::Push, vec![EAX.into()]).is_err());
+        assert!(op(Opcode::Set(Condition::Less), vec![EAX.into()]).is_err());
+        assert!(op(Opcode::Movzx, vec![EAX.into(), EBX.into()]).is_err());
+        assert!(op(Opcode::Movzx, vec![local(WordSize::Dword), CL.into()]).is_err());
+    }
+
+    #[test]
+    fn keeps_xmm_registers_to_sse_instructions() {
+        assert!(op(Opcode::Add, vec![RAX.into(), XMM0.into()]).is_err());
+        assert!(op(Opcode::Mov, vec![XMM0.into(), RAX.into()]).is_err());
+        assert!(op(Opcode::Addsd, vec![RAX.into(), XMM0.into()]).is_err());
+        assert!(op(Opcode::Mulsd, vec![local(WordSize::Qword), XMM0.into()]).is_err());
+        assert!(op(Opcode::Cvttsd2si, vec![XMM1.into(), XMM0.into()]).is_err());
+        assert!(op(Opcode::Cvttsd2si, vec![local(WordSize::Dword), XMM0.into()]).is_err());
+    }
+}
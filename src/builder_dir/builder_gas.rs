@@ -1,311 +1,216 @@
-use crate::builder_dir::return_code::{Code, Return};
+use crate::builder_dir::backend::{align_instruction, Backend, BuilderState, Opcode, Operand, WordSize};
+
+fn suffix(size: WordSize) -> &'static str {
+    match size {
+        WordSize::Byte => "b",
+        WordSize::Word => "w",
+        WordSize::Dword => "l",
+        WordSize::Qword => "q",
+    }
+}
+// .asciz escapes, anything outside printable ascii as an octal escape
+fn escape_string(value: &str) -> String {
+    let mut escaped = String::new();
+    for byte in value.bytes() {
+        match byte {
+            b'"' => escaped += "\\\"",
+            b'\\' => escaped += "\\\\",
+            0x20..=0x7e => escaped.push(byte as char),
+            _ => escaped += &format!("\\{:03o}", byte),
+        }
+    }
+    escaped
+}
 
 const ASM_BASE_START: &str = r#".text
     .global _start
 "#;
 
+#[allow(dead_code)]
 const ASM_BASE_END: &str = r#"
     _start:
         call            main
-        mov             rbx, rax
+        movq            %rax, %rbx
 
-        mov             rax, 60                 ; system call for exit
-        mov             rdi, rbx
+        movq            $60, %rax               # system call for exit
+        movq            %rbx, %rdi
         syscall
 "#;
 
 const ASM_BASE_BSS: &str = ".bss\n";
-const ASM_BASE_DATA: &str = ".data\n";
+// marks the stack as not executable, ld warns about it otherwise
+const ASM_BASE_DATA: &str = ".section .note.GNU-stack,\"\",@progbits\n.data\n";
 
 pub struct Builder {
-    text: String,
-    bss: String,
-    data: String,
-    function_body: String,
-    function_head: String,
-    is_function_currently: bool,
-    local_variables: Vec<u32>,
-    local_variables_offset: Vec<u32>,
-    local_offset: u32,
-    pub(crate) func_args_order: Vec<String>,
-    syscall_args_ordering: Vec<String>,
+    state: BuilderState,
 }
 
 impl Default for Builder {
     fn default() -> Self {
         Self {
-            text: ASM_BASE_START.to_string(),
-            bss: ASM_BASE_BSS.to_string(),
-            data: ASM_BASE_DATA.to_string(),
-            function_body: "".to_string(),
-            function_head: "".to_string(),
-            is_function_currently: false,
-            local_variables: vec![],
-            local_variables_offset: vec![],
-            local_offset: 0,
-            syscall_args_ordering: vec![
-                "rax".to_string(),
-                "rdi".to_string(),
-                "rsi".to_string(),
-                "rdx".to_string(),
-                "r10".to_string(),
-                "r8".to_string(),
-                "r9".to_string(),
-            ],
-            func_args_order: vec![
-                "edi".to_string(),
-                "esi".to_string(),
-                "edx".to_string(),
-                "ecx".to_string(),
-                "r8d".to_string(),
-                "r9d".to_string(),
-            ],
+            state: BuilderState::new(ASM_BASE_START, ASM_BASE_BSS, ASM_BASE_DATA),
         }
     }
 }
 impl Builder {
+    #[allow(dead_code)]
     pub fn new() -> Self {
         Builder::default()
     }
-    pub fn new_program(start_function: &str) -> Self {
-        Self {
-            text: ASM_BASE_START.to_string().replace("_start", start_function),
-            ..Builder::default()
-        }
-    }
-    pub fn set_func_args_order(&mut self, order: Vec<String>) -> Return {
-        if order.len() == self.func_args_order.len() {
-            Return::new(
-                format!(
-                    "Length of order is not equal to base order, expected {} size got {} instead",
-                    order.len(),
-                    self.func_args_order.len()
-                ),
-                Code::BadArguments,
-            )
-        } else {
-            self.func_args_order = order;
-            Return::new("".to_string(), Code::Good)
+    // AT&T operands, `%reg`, `$imm`, `offset(%base)`
+    fn render_operand(&self, operand: &Operand) -> String {
+        match operand {
+            Operand::Register(register) => format!("%{}", register.name()),
+            Operand::Immediate(value) => format!("${}", value),
+            Operand::Memory(memory) => match memory.offset {
+                0 => format!("(%{})", memory.base.name()),
+                offset => format!("{}(%{})", offset, memory.base.name()),
+            },
+            Operand::Label(label) => format!("${}", label),
+            Operand::Data { label, .. } => format!("{}(%rip)", label),
         }
     }
-    pub fn add_line_text(&mut self, line: &str) {
-        self.text += &*format!("\t{}\n", line)
-    }
-    pub fn add_value_text(&mut self, line: &str) {
-        self.text += &*format!("\t\t{}\n", line)
-    }
-    pub fn add_line_function(&mut self, line: &str) {
-        self.function_body += &*format!("\t{}\n", line)
-    }
-    pub fn add_value_function(&mut self, line: &str) {
-        self.function_body += &*format!("\t\t{}\n", line)
-    }
-    pub fn add_line_function_head(&mut self, line: &str) {
-        self.function_head += &*format!("\t{}\n", line)
-    }
-    pub fn add_value_function_head(&mut self, line: &str) {
-        self.function_head += &*format!("\t\t{}\n", line)
-    }
-    pub fn add_line_bss(&mut self, line: &str) {
-        self.bss += &*format!("\t{}\n", line)
-    }
-    pub fn add_value_bss(&mut self, line: &str) {
-        self.bss += &*format!("\t\t{}\n", line)
-    }
-    pub fn add_line_data(&mut self, line: &str) {
-        self.data += &*format!("\t{}\n", line)
-    }
-    pub fn add_value_data(&mut self, line: &str) {
-        self.data += &*format!("\t\t{}\n", line)
-    }
-
-    pub fn add_built_in_function(&mut self, function: &str) {
-        self.text += function;
+    // the operand size every instruction with operands has to spell out in its suffix
+    fn operand_size(&self, operands: &[Operand]) -> Option<WordSize> {
+        operands
+            .iter()
+            .find_map(Operand::size)
+            .or_else(|| (!operands.is_empty()).then_some(WordSize::Qword))
     }
+}
 
-    pub fn open_function(&mut self, function_name: &str) -> Return {
-        if self.is_function_currently {
-            return Return::new(
-                "Cannot Create Function Within a Function".to_string(),
-                Code::FunctionWithinFunctionErr,
-            );
+impl Backend for Builder {
+    fn new_program(start_function: &str) -> Self {
+        Self {
+            state: BuilderState::new(
+                &ASM_BASE_START.replace("_start", start_function),
+                ASM_BASE_BSS,
+                ASM_BASE_DATA,
+            ),
         }
-        self.is_function_currently = true;
-        self.function_head += &*format!("{}:\n", function_name);
-        self.add_value_function_head(&*format!("push\t\t\trbp"));
-        self.add_value_function_head(&*format!("mov\t\t\t\trbp, rsp"));
-
-        Return::new("Everything Is Fine".to_string(), Code::Good)
     }
-
-    pub fn close_function(&mut self) -> Return {
-        if !self.is_function_currently {
-            return Return::new(
-                "Is not in a Function".to_string(),
-                Code::ClosingOfNonFunctionErr,
-            );
-        }
-        if self.local_offset > 0 {
-            self.add_value_function_head(&*format!("sub\t\t\t\trsp, {}", self.local_offset / 12 * 2 + 12));
-        }
-        self.local_offset = 0;
-        self.local_variables = vec![];
-        self.local_variables_offset = vec![];
-        self.is_function_currently = false;
-        self.pop("rbp");
-        self.add_value_function("ret");
-        self.function_head += &*self.function_body.clone();
-        self.add_line_text(&*self.function_head.clone());
-        self.function_body = "".to_string();
-        self.function_head = "".to_string();
-        Return::new("".to_string(), Code::Good)
+    fn state(&self) -> &BuilderState {
+        &self.state
     }
-
-    pub fn local_word_assign(&mut self, loc_offset: u32, word_size: &str, value_or_register: &str) -> Return{
-        if !self.is_function_currently {
-            return Return::new(
-                "cannot assign local variable not in function".to_string(),
-                Code::LocalVariableNotInFunction,
+    fn state_mut(&mut self) -> &mut BuilderState {
+        &mut self.state
+    }
+    fn render_instruction(&self, op: Opcode, operands: &[Operand]) -> String {
+        // addresses are loaded relative to rip so the output also links as a PIE
+        if let (Opcode::Mov, [Operand::Register(register), Operand::Label(label)]) = (op, operands) {
+            return align_instruction(
+                "leaq",
+                &format!("{}(%rip), %{}", label, register.with_size(WordSize::Qword).name()),
             );
         }
-        let offset = self.local_offset + loc_offset;
-        self.local_offset += loc_offset;
-        self.local_variables.push(loc_offset);
-        self.local_variables_offset.push(offset);
-        self.mov(&*format!("{} [rsp - {}]", word_size, offset), value_or_register);
-        Return::new("".to_string(), Code::Good)
-    }
-
-    pub fn new_local_word(&mut self, value: u16) -> Return {
-        self.local_word_assign(2, "word", &*value.to_string())
-    }
-    pub fn new_local_dword(&mut self, value: u32) -> Return {
-        self.local_word_assign(4, "dword", &*value.to_string())
-    }
-    pub fn new_local_qword(&mut self, value: u64) -> Return {
-        self.local_word_assign(8, "qword", &*value.to_string())
-    }
-
-    pub fn get_local_word_size_and_offset(&self, id: usize) -> (Return, u32, u32) {
-        if id >= self.local_variables.len() {
-            (
-                Return::new("Out Of Range".to_string(), Code::OutOfRange),
-                0,
-                0,
-            )
-        } else {
-            let word_size: u32 = self.local_variables.get(id).unwrap().clone() as u32;
-            let word_offset: u32 = self.local_variables_offset.get(id).unwrap().clone() as u32;
+        // setcc and the sse instructions have no size suffix, setb would read as set if
+        // below, only cvtsi2sd needs the size of its int source
+        let op = match (op, self.operand_size(operands)) {
+            // movzbl, the source size and then the destination size
+            (Opcode::Movzx, Some(size)) => format!("movzb{}", suffix(size)),
+            (Opcode::Cvtsi2sd, _) => match operands[1].size() {
+                Some(size) => format!("{}{}", op.name(), suffix(size)),
+                None => op.name().to_string(),
+            },
             (
-                Return::new("".to_string(), Code::Good),
-                word_size,
-                word_offset,
+                Opcode::Set(_)
+                | Opcode::Movsd
+                | Opcode::Addsd
+                | Opcode::Subsd
+                | Opcode::Mulsd
+                | Opcode::Divsd
+                | Opcode::Ucomisd
+                | Opcode::Cvttsd2si,
+                _,
             )
-        }
-    }
-
-    pub fn new_len_addr(&mut self, new_addr: &str, from_addr: &str) {
-        self.add_line_data(&*format!(r#"{}: equ $ - {}"#, new_addr, from_addr));
-    }
-
-    pub fn new_string_literal(&mut self, addr: &str, value: &str) {
-        let mut new_string = format!(r#"{}: db "#, addr);
-        let mut len = 0;
-        for character in value.clone().chars() {
-            new_string += &*(character as u8).to_string();
-            len += 1;
-            if len != value.len() {
-                new_string += ", ";
-            } else {
-                new_string += ", 0";
-            }
-        }
-        self.add_line_data(&*new_string);
-    }
-
-    // pub fn new_string_literal_with_len(&mut self, addr: &str, value: &str) {
-    //     self.new_string_literal(addr, value);
-    //     self.add_line_data(&*format!(".len: equ $ - {}", addr))
-    // }
-    pub fn call_function(&mut self, function: &str, arguments: Vec<&str>) {
-        let mut len = -1;
-        for argument in arguments {
-            len += 1;
-            let register = &*self.func_args_order.get(len as usize).unwrap().clone();
-            self.mov(register, argument);
-        }
-        self.call(function);
+            | (_, None) => op.name().to_string(),
+            (_, Some(size)) => format!("{}{}", op.name(), suffix(size)),
+        };
+        // source first, destination last
+        let operands: Vec<String> = operands
+            .iter()
+            .rev()
+            .map(|operand| self.render_operand(operand))
+            .collect();
+        align_instruction(&op, &operands.join(", "))
     }
-
-    // pub fn base(&mut self, arg1: &str, arg2: &str){}
-    pub fn extern_add(&mut self, function_or_address: &str) {
-        self.add_line_text(&*format!(".extern\t\t\t\t{}", function_or_address))
-    }
-    pub fn new_syscall(&mut self) {
-        self.add_raw_asm1("syscall");
-    }
-    pub fn pop(&mut self, register: &str) {
-        self.add_raw_asm2("pop", register)
-    }
-    pub fn push(&mut self, value_or_register: &str) {
-        self.add_raw_asm2("push", value_or_register)
-    }
-    pub fn call(&mut self, function: &str) {
-        self.add_raw_asm2("call", function)
-    }
-    pub fn mov(&mut self, value_or_register: &str, register: &str) {
-        self.add_raw_asm3("mov", value_or_register, register);
-    }
-    pub fn add(&mut self, value_or_register: &str, register: &str) {
-        self.add_raw_asm3("add", value_or_register, register);
-    }
-    pub fn sub(&mut self, value_or_register: &str, register: &str) {
-        self.add_raw_asm3("sub", value_or_register, register);
+    fn global_directive(&self, name: &str) -> String {
+        format!(".global\t\t\t\t{}", name)
     }
-    pub fn div(&mut self, value_or_register: &str, register: &str) {
-        self.add_raw_asm3("div", value_or_register, register);
+    fn extern_directive(&self, name: &str) -> String {
+        format!(".extern\t\t\t\t{}", name)
     }
-    pub fn mul(&mut self, value_or_register: &str, register: &str) {
-        self.add_raw_asm3("mul", value_or_register, register);
+    fn string_directive(&self, label: &str, value: &str) -> String {
+        format!("{}: .asciz \"{}\"", label, escape_string(value))
     }
-
-    pub fn add_raw_asm1(&mut self, op: &str) {
-        self.add_value_function(op)
-    }
-    pub fn add_raw_asm2(&mut self, op: &str, left: &str) {
-        if op.len() > 3 {
-            self.add_value_function(&*format!("{}\t\t\t{}", op, left))
-        } else {
-            self.add_value_function(&*format!("{}\t\t\t\t{}", op, left))
-        }
+    fn float_directive(&self, label: &str, value: f64) -> String {
+        format!("{}: .quad {:#x}", label, value.to_bits())
     }
-    pub fn add_raw_asm3(&mut self, op: &str, left: &str, right: &str) {
-        if op.len() > 3 {
-            self.add_value_function(&*format!("{}\t\t\t{}, {}", op, left, right))
-        } else {
-            self.add_value_function(&*format!("{}\t\t\t\t{}, {}", op, left, right))
-        }
+    fn quad_directive(&self, value: u64) -> String {
+        format!(".quad {}", value)
     }
-
-    pub fn syscall(&mut self, arguments: Vec<&str>) {
-        let mut len = -1;
-        for arg in arguments {
-            len += 1;
-            let register = &*(self
-                .syscall_args_ordering
-                .get(len as usize)
-                .unwrap()
-                .clone() as String);
-            self.mov(register, arg);
-        }
-        self.new_syscall();
+    fn start_code(&self) -> &'static str {
+        ASM_BASE_END
     }
+}
 
-    pub fn build(&mut self) -> String {
-        self.text += ASM_BASE_END;
-        self.data.to_owned() + &*self.bss + &*self.text
-    }
-    pub fn build_no_start(&mut self) -> String {
-        self.data.to_owned() + &*self.bss + &*self.text
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder_dir::backend::{imm, label, Condition, Memory, DIL, EAX, ECX, RAX, RBP, XMM0};
+
+    // the rendered instruction with its column padding collapsed
+    fn render(op: Opcode, operands: Vec<Operand>) -> String {
+        let rendered = Builder::new_program("main").render_instruction(op, &operands);
+        rendered.split_whitespace().collect::<Vec<_>>().join(" ")
+    }
+    fn local(size: WordSize, offset: i32) -> Operand {
+        Operand::Memory(Memory {
+            size,
+            base: RBP,
+            offset,
+        })
+    }
+
+    #[test]
+    fn operands_are_written_source_first() {
+        assert_eq!(render(Opcode::Mov, vec![EAX.into(), imm(5)]), "movl $5, %eax");
+        assert_eq!(render(Opcode::Add, vec![EAX.into(), ECX.into()]), "addl %ecx, %eax");
+        assert_eq!(render(Opcode::Cmp, vec![RAX.into(), imm(-1)]), "cmpq $-1, %rax");
+    }
+
+    #[test]
+    fn suffixes_follow_the_operand_size() {
+        assert_eq!(render(Opcode::Movzx, vec![EAX.into(), DIL.into()]), "movzbl %dil, %eax");
+        assert_eq!(render(Opcode::Set(Condition::Equal), vec![DIL.into()]), "sete %dil");
+        assert_eq!(render(Opcode::Cvtsi2sd, vec![XMM0.into(), EAX.into()]), "cvtsi2sdl %eax, %xmm0");
+        assert_eq!(render(Opcode::Cdq, vec![]), "cdq");
+    }
+
+    #[test]
+    fn memory_operands() {
+        assert_eq!(render(Opcode::Mov, vec![local(WordSize::Dword, -4), EAX.into()]), "movl %eax, -4(%rbp)");
+        assert_eq!(render(Opcode::Mov, vec![RAX.into(), local(WordSize::Qword, 0)]), "movq (%rbp), %rax");
+        let float = Operand::Data {
+            size: WordSize::Qword,
+            label: "local_float_0".to_string(),
+        };
+        assert_eq!(render(Opcode::Movsd, vec![XMM0.into(), float]), "movsd local_float_0(%rip), %xmm0");
+        // addresses are loaded rip relative
+        assert_eq!(
+            render(Opcode::Mov, vec![EAX.into(), label("local_string_0")]),
+            "leaq local_string_0(%rip), %rax"
+        );
+    }
+
+    #[test]
+    fn strings_are_escaped() {
+        assert_eq!(escape_string("plain text"), "plain text");
+        assert_eq!(escape_string("say \"hi\""), "say \\\"hi\\\"");
+        assert_eq!(escape_string("a\\b"), "a\\\\b");
+        assert_eq!(escape_string("tab\tnewline\n"), "tab\\011newline\\012");
+        assert_eq!(escape_string("é"), "\\303\\251");
+        let builder = Builder::new_program("main");
+        assert_eq!(builder.string_directive("s", "%d\n"), "s: .asciz \"%d\\012\"");
     }
 }
@@ -1,9 +1,10 @@
-use crate::builder_dir::return_code::{Code, Return};
+use crate::builder_dir::backend::{align_instruction, Backend, BuilderState, Opcode, Operand, TextItem, WordSize};
 
 const ASM_BASE_START: &str = r#"section .text
     global _start
 "#;
 
+#[allow(dead_code)]
 const ASM_BASE_END: &str = r#"
     _start:
         call            main
@@ -15,297 +16,179 @@ const ASM_BASE_END: &str = r#"
 "#;
 
 const ASM_BASE_BSS: &str = "section .bss\n";
-const ASM_BASE_DATA: &str = "section .data\n";
+// marks the stack as not executable, ld warns about it otherwise
+const ASM_BASE_DATA: &str = "section .note.GNU-stack noalloc noexec nowrite progbits\nsection .data\n";
 
 pub struct Builder {
-    text: String,
-    bss: String,
-    data: String,
-    function_body: String,
-    function_head: String,
-    is_function_currently: bool,
-    local_variables: Vec<u32>,
-    local_variables_offset: Vec<u32>,
-    local_offset: u32,
-    pub(crate) func_args_order: Vec<String>,
-    syscall_args_ordering: Vec<String>,
+    state: BuilderState,
 }
 
 impl Default for Builder {
     fn default() -> Self {
         Self {
-            text: ASM_BASE_START.to_string(),
-            bss: ASM_BASE_BSS.to_string(),
-            data: ASM_BASE_DATA.to_string(),
-            function_body: "".to_string(),
-            function_head: "".to_string(),
-            is_function_currently: false,
-            local_variables: vec![],
-            local_variables_offset: vec![],
-            local_offset: 0,
-            syscall_args_ordering: vec![
-                "rax".to_string(),
-                "rdi".to_string(),
-                "rsi".to_string(),
-                "rdx".to_string(),
-                "r10".to_string(),
-                "r8".to_string(),
-                "r9".to_string(),
-            ],
-            func_args_order: vec![
-                "edi".to_string(),
-                "esi".to_string(),
-                "edx".to_string(),
-                "ecx".to_string(),
-                "r8d".to_string(),
-                "r9d".to_string(),
-            ],
+            state: BuilderState::new(ASM_BASE_START, ASM_BASE_BSS, ASM_BASE_DATA),
         }
     }
 }
 impl Builder {
+    #[allow(dead_code)]
     pub fn new() -> Self {
         Builder::default()
     }
-    pub fn new_program(start_function: &str) -> Self {
-        Self {
-            text: ASM_BASE_START.to_string().replace("_start", start_function),
-            ..Builder::default()
-        }
-    }
-    pub fn set_func_args_order(&mut self, order: Vec<String>) -> Return {
-        if order.len() == self.func_args_order.len() {
-            Return::new(
-                format!(
-                    "Length of order is not equal to base order, expected {} size got {} instead",
-                    order.len(),
-                    self.func_args_order.len()
-                ),
-                Code::BadArguments,
-            )
-        } else {
-            self.func_args_order = order;
-            Return::new("".to_string(), Code::Good)
-        }
-    }
-    pub fn add_line_text(&mut self, line: &str) {
-        self.text += &*format!("\t{}\n", line)
-    }
-    pub fn add_value_text(&mut self, line: &str) {
-        self.text += &*format!("\t\t{}\n", line)
-    }
-    pub fn add_line_function(&mut self, line: &str) {
-        self.function_body += &*format!("\t{}\n", line)
-    }
-    pub fn add_value_function(&mut self, line: &str) {
-        self.function_body += &*format!("\t\t{}\n", line)
-    }
-    pub fn add_line_function_head(&mut self, line: &str) {
-        self.function_head += &*format!("\t{}\n", line)
-    }
-    pub fn add_value_function_head(&mut self, line: &str) {
-        self.function_head += &*format!("\t\t{}\n", line)
-    }
-    pub fn add_line_bss(&mut self, line: &str) {
-        self.bss += &*format!("\t{}\n", line)
-    }
-    pub fn add_value_bss(&mut self, line: &str) {
-        self.bss += &*format!("\t\t{}\n", line)
-    }
-    pub fn add_line_data(&mut self, line: &str) {
-        self.data += &*format!("\t{}\n", line)
-    }
-    pub fn add_value_data(&mut self, line: &str) {
-        self.data += &*format!("\t\t{}\n", line)
-    }
-
-    pub fn add_built_in_function(&mut self, function: &str) {
-        self.text += function;
-    }
-
-    pub fn open_function(&mut self, function_name: &str) -> Return {
-        if self.is_function_currently {
-            return Return::new(
-                "Cannot Create Function Within a Function".to_string(),
-                Code::FunctionWithinFunctionErr,
-            );
-        }
-        self.is_function_currently = true;
-        self.function_head += &*format!("{}:\n", function_name);
-        self.add_value_function_head(&*format!("push\t\t\trbp"));
-        self.add_value_function_head(&*format!("mov\t\t\t\trbp, rsp"));
-
-        Return::new("Everything Is Fine".to_string(), Code::Good)
-    }
-
-    pub fn close_function(&mut self) -> Return {
-        if !self.is_function_currently {
-            return Return::new(
-                "Is not in a Function".to_string(),
-                Code::ClosingOfNonFunctionErr,
-            );
-        }
-        if self.local_offset > 0 {
-            self.add_value_function_head(&*format!("sub\t\t\t\trsp, {}", self.local_offset / 12 * 2 + 12));
-        }
-        self.local_offset = 0;
-        self.local_variables = vec![];
-        self.local_variables_offset = vec![];
-        self.is_function_currently = false;
-        self.pop("rbp");
-        self.add_value_function("ret");
-        self.function_head += &*self.function_body.clone();
-        self.add_line_text(&*self.function_head.clone());
-        self.function_body = "".to_string();
-        self.function_head = "".to_string();
-        Return::new("".to_string(), Code::Good)
-    }
-
-    pub fn local_word_assign(&mut self, loc_offset: u32, word_size: &str, value_or_register: &str) -> Return{
-        if !self.is_function_currently {
-            return Return::new(
-                "cannot assign local variable not in function".to_string(),
-                Code::LocalVariableNotInFunction,
-            );
-        }
-        let offset = self.local_offset + loc_offset;
-        self.local_offset += loc_offset;
-        self.local_variables.push(loc_offset);
-        self.local_variables_offset.push(offset);
-        self.mov(&*format!("{} [rsp - {}]", word_size, offset), value_or_register);
-        Return::new("".to_string(), Code::Good)
-    }
-
-    pub fn new_local_word(&mut self, value: u16) -> Return {
-        self.local_word_assign(2, "word", &*value.to_string())
-    }
-    pub fn new_local_dword(&mut self, value: u32) -> Return {
-        self.local_word_assign(4, "dword", &*value.to_string())
-    }
-    pub fn new_local_qword(&mut self, value: u64) -> Return {
-        self.local_word_assign(8, "qword", &*value.to_string())
-    }
-
-    pub fn get_local_word_size_and_offset(&self, id: usize) -> (Return, u32, u32) {
-        if id >= self.local_variables.len() {
-            (
-                Return::new("Out Of Range".to_string(), Code::OutOfRange),
-                0,
-                0,
-            )
-        } else {
-            let word_size: u32 = self.local_variables.get(id).unwrap().clone() as u32;
-            let word_offset: u32 = self.local_variables_offset.get(id).unwrap().clone() as u32;
-            (
-                Return::new("".to_string(), Code::Good),
-                word_size,
-                word_offset,
-            )
-        }
-    }
-
+    #[allow(dead_code)]
     pub fn new_len_addr(&mut self, new_addr: &str, from_addr: &str) {
-        self.add_line_data(&*format!(r#"{}: equ $ - {}"#, new_addr, from_addr));
+        self.add_line_data(&format!(r#"{}: equ $ - {}"#, new_addr, from_addr));
     }
-
-    pub fn new_string_literal(&mut self, addr: &str, value: &str) {
-        let mut new_string = format!(r#"{}: db "#, addr);
-        let mut len = 0;
-        for character in value.clone().chars() {
-            new_string += &*(character as u8).to_string();
-            len += 1;
-            if len != value.len() {
-                new_string += ", ";
-            } else {
-                new_string += ", 0";
-            }
-        }
-        self.add_line_data(&*new_string);
-    }
-
+    #[allow(dead_code)]
     pub fn new_string_literal_with_len(&mut self, addr: &str, value: &str) {
         self.new_string_literal(addr, value);
-        self.add_line_data(&*format!(".len: equ $ - {}", addr))
-    }
-    pub fn call_function(&mut self, function: &str, arguments: Vec<&str>) {
-        let mut len = -1;
-        for argument in arguments {
-            len += 1;
-            let register = &*self.func_args_order.get(len as usize).unwrap().clone();
-            self.mov(register, argument);
+        self.add_line_data(&format!(".len: equ $ - {}", addr))
+    }
+    fn render_operand(&self, operand: &Operand) -> String {
+        match operand {
+            Operand::Register(register) => register.name(),
+            Operand::Immediate(value) => value.to_string(),
+            Operand::Memory(memory) => match memory.offset {
+                0 => format!("{} [{}]", memory.size.name(), memory.base.name()),
+                offset if offset < 0 => {
+                    format!("{} [{} - {}]", memory.size.name(), memory.base.name(), -offset)
+                }
+                offset => format!("{} [{} + {}]", memory.size.name(), memory.base.name(), offset),
+            },
+            Operand::Label(label) => label.clone(),
+            Operand::Data { size, label } => format!("{} [rel {}]", size.name(), label),
         }
-        self.call(function);
     }
+}
 
-    // pub fn base(&mut self, arg1: &str, arg2: &str){}
-    pub fn extern_add(&mut self, function_or_address: &str) {
-        self.add_line_text(&*format!("extern\t\t\t\t{}", function_or_address))
-    }
-    pub fn new_syscall(&mut self) {
-        self.add_raw_asm1("syscall");
-    }
-    pub fn pop(&mut self, register: &str) {
-        self.add_raw_asm2("pop", register)
-    }
-    pub fn push(&mut self, value_or_register: &str) {
-        self.add_raw_asm2("push", value_or_register)
-    }
-    pub fn call(&mut self, function: &str) {
-        self.add_raw_asm2("call", function)
-    }
-    pub fn mov(&mut self, register: &str, value_or_register: &str) {
-        self.add_raw_asm3("mov", register, value_or_register);
-    }
-    pub fn add(&mut self, register: &str, value_or_register: &str) {
-        self.add_raw_asm3("add", register, value_or_register);
-    }
-    pub fn sub(&mut self, register: &str, value_or_register: &str) {
-        self.add_raw_asm3("sub", register, value_or_register);
-    }
-    pub fn div(&mut self, register: &str, value_or_register: &str) {
-        self.add_raw_asm3("div", register, value_or_register);
+impl Backend for Builder {
+    fn new_program(start_function: &str) -> Self {
+        Self {
+            state: BuilderState::new(
+                &ASM_BASE_START.replace("_start", start_function),
+                ASM_BASE_BSS,
+                ASM_BASE_DATA,
+            ),
+        }
     }
-    pub fn mul(&mut self, register: &str, value_or_register: &str) {
-        self.add_raw_asm3("mul", register, value_or_register);
+    fn state(&self) -> &BuilderState {
+        &self.state
     }
-
-    pub fn add_raw_asm1(&mut self, op: &str) {
-        self.add_value_function(op)
+    fn state_mut(&mut self) -> &mut BuilderState {
+        &mut self.state
     }
-    pub fn add_raw_asm2(&mut self, op: &str, left: &str) {
-        if op.len() > 3 {
-            self.add_value_function(&*format!("{}\t\t\t{}", op, left))
-        } else {
-            self.add_value_function(&*format!("{}\t\t\t\t{}", op, left))
+    fn render_instruction(&self, op: Opcode, operands: &[Operand]) -> String {
+        // rip relative like the gas backend, an absolute address does not link as a PIE
+        if let (Opcode::Mov, [Operand::Register(register), Operand::Label(label)]) = (op, operands) {
+            return align_instruction(
+                "lea",
+                &format!("{}, [rel {}]", register.with_size(WordSize::Qword).name(), label),
+            );
         }
-    }
-    pub fn add_raw_asm3(&mut self, op: &str, left: &str, right: &str) {
-        if op.len() > 3 {
-            self.add_value_function(&*format!("{}\t\t\t{}, {}", op, left, right))
+        let operands: Vec<String> = operands
+            .iter()
+            .map(|operand| self.render_operand(operand))
+            .collect();
+        align_instruction(op.name(), &operands.join(", "))
+    }
+    fn global_directive(&self, name: &str) -> String {
+        format!("global\t\t\t\t{}", name)
+    }
+    fn extern_directive(&self, name: &str) -> String {
+        format!("extern\t\t\t\t{}", name)
+    }
+    fn string_directive(&self, label: &str, value: &str) -> String {
+        let mut bytes: Vec<String> = value.bytes().map(|byte| byte.to_string()).collect();
+        bytes.push("0".to_string());
+        format!("{}: db {}", label, bytes.join(", "))
+    }
+    fn float_directive(&self, label: &str, value: f64) -> String {
+        format!("{}: dq {:#x}", label, value.to_bits())
+    }
+    fn quad_directive(&self, value: u64) -> String {
+        format!("dq {}", value)
+    }
+    fn start_code(&self) -> &'static str {
+        ASM_BASE_END
+    }
+    fn call_target(&self, function: &str) -> String {
+        let external = self
+            .state
+            .text_items
+            .iter()
+            .any(|item| matches!(item, TextItem::Extern(name) if name == function));
+        if external {
+            format!("{} wrt ..plt", function)
         } else {
-            self.add_value_function(&*format!("{}\t\t\t\t{}, {}", op, left, right))
+            function.to_string()
         }
     }
+}
 
-    pub fn syscall(&mut self, arguments: Vec<&str>) {
-        let mut len = -1;
-        for arg in arguments {
-            len += 1;
-            let register = &*(self
-                .syscall_args_ordering
-                .get(len as usize)
-                .unwrap()
-                .clone() as String);
-            self.mov(register, arg);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder_dir::backend::{label, Dialect, Instr, EDI};
+    use crate::minimal_lang::{compile, Emit};
+    use crate::source_map::SourceMap;
+    use std::process::Command;
+
+    #[test]
+    fn label_addresses_are_rip_relative() {
+        let builder = Builder::new_program("main");
+        let rendered = builder.render_instruction(Opcode::Mov, &[EDI.into(), label("local_string_0")]);
+        assert_eq!(rendered, align_instruction("lea", "rdi, [rel local_string_0]"));
+    }
+
+    #[test]
+    fn extern_calls_go_through_the_plt() {
+        let mut builder = Builder::new_program("main");
+        builder.state_mut().text_items.push(TextItem::Extern("printf".to_string()));
+        assert_eq!(
+            builder.render(&Instr::Call("printf".to_string())),
+            format!("\t\t{}", align_instruction("call", "printf wrt ..plt"))
+        );
+        assert_eq!(
+            builder.render(&Instr::Call("put_i".to_string())),
+            format!("\t\t{}", align_instruction("call", "put_i"))
+        );
+    }
+    #[test]
+    fn the_stack_is_marked_non_executable() {
+        let builder = Builder::new_program("main");
+        assert!(builder
+            .build_no_start()
+            .starts_with("section .note.GNU-stack noalloc noexec nowrite progbits\n"));
+    }
+
+    // only runs where nasm is installed, there is nothing to check the output with otherwise
+    #[test]
+    fn output_assembles_and_links() {
+        if Command::new("nasm").arg("-v").output().is_err() {
+            eprintln!("nasm not found, skipping");
+            return;
         }
-        self.new_syscall();
-    }
-
-    pub fn build(&mut self) -> String {
-        self.text += ASM_BASE_END;
-        self.data.to_owned() + &*self.bss + &*self.text
-    }
-    pub fn build_no_start(&mut self) -> String {
-        self.data.to_owned() + &*self.bss + &*self.text
+        let dir = std::env::temp_dir().join(format!("minimal_lang_nasm_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let source = dir.join("main.min");
+        std::fs::write(
+            &source,
+            "fun main() -> int {\n    printf(\"%s %d\\n\", \"ok\", 6 * 7);\n    return 0;\n}\n",
+        )
+        .unwrap();
+        let mut source_map = SourceMap::new();
+        let asm = compile(&mut source_map, source.to_str().unwrap(), Emit::Asm(Dialect::Nasm), false)
+            .expect("compiling failed");
+        std::fs::write(dir.join("out.asm"), asm).unwrap();
+        let run = |program: &str, args: &[&str]| {
+            let output = Command::new(program).args(args).current_dir(&dir).output().unwrap();
+            assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+            output
+        };
+        run("nasm", &["-f", "elf64", "-o", "out.o", "out.asm"]);
+        run("cc", &["-o", "out", "out.o"]);
+        let output = run(dir.join("out").to_str().unwrap(), &[]);
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "ok 42\n");
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 }
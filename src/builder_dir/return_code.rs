@@ -1,3 +1,4 @@
+#[allow(dead_code)]
 pub enum Code {
     Good,
     NameErr,
@@ -8,6 +9,7 @@ pub enum Code {
     LocalVariableNotInFunction,
 }
 
+#[allow(dead_code)]
 pub struct Return {
     pub message: String,
     pub code: Code,
@@ -0,0 +1,460 @@
+use crate::diagnostic::Diagnostic;
+use crate::parser::{BinaryOperation, ConstValue, Node, UnaryOperation, VariableType};
+use crate::resolver::{SymbolId, SymbolKind, SymbolTable};
+use crate::source_map::Span;
+
+pub fn type_name(var_type: VariableType) -> &'static str {
+    match var_type {
+        VariableType::String => "string",
+        VariableType::Integer => "int",
+        VariableType::Boolean => "bool",
+        VariableType::FloatingPoint => "float",
+        VariableType::Char => "char",
+        VariableType::Void => "void",
+    }
+}
+
+fn is_true(condition: &Node) -> bool {
+    match condition {
+        Node::Expr { value, .. } => is_true(value),
+        Node::Const {
+            value_type: ConstValue::Boolean { value },
+            ..
+        } => *value,
+        _ => false,
+    }
+}
+// whether a break in the block leaves the loop the block belongs to
+fn breaks(body: &[Node]) -> bool {
+    body.iter().any(|node| match node {
+        Node::Break { .. } => true,
+        Node::If {
+            body, else_body, ..
+        } => breaks(body) || breaks(else_body),
+        _ => false,
+    })
+}
+// whether every path through the block ends in a return
+fn always_returns(body: &[Node]) -> bool {
+    body.iter().any(|node| match node {
+        Node::Return { .. } => true,
+        Node::If {
+            body, else_body, ..
+        } => always_returns(body) && always_returns(else_body),
+        // never falls through, it can only be left with a return
+        Node::While {
+            condition, body, ..
+        } => is_true(condition) && !breaks(body),
+        _ => false,
+    })
+}
+
+// walks the resolved AST before the compiler, infers every expression type and reports mismatches
+pub struct Checker<'a> {
+    symbols: &'a SymbolTable,
+    return_type: VariableType,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl<'a> Checker<'a> {
+    pub fn new(symbols: &'a SymbolTable) -> Self {
+        Self {
+            symbols,
+            return_type: VariableType::Void,
+            diagnostics: vec![],
+        }
+    }
+    fn error(&mut self, code: &'static str, message: String, span: Span) {
+        self.diagnostics.push(Diagnostic::error(code, message, span));
+    }
+    fn mismatch(&mut self, expected: VariableType, found: VariableType, span: Span) {
+        self.error(
+            "E0401",
+            format!(
+                "mismatched types, expected {} but found {}",
+                type_name(expected),
+                type_name(found)
+            ),
+            span,
+        );
+    }
+
+    // None when the type could not be worked out, the reason is already reported
+    fn expression(&mut self, node: &Node) -> Option<VariableType> {
+        match node {
+            Node::Expr { value, .. } => self.expression(value),
+            Node::Const { value_type, .. } => Some(match value_type {
+                ConstValue::String { .. } => VariableType::String,
+                ConstValue::Integer { .. } => VariableType::Integer,
+                ConstValue::Boolean { .. } => VariableType::Boolean,
+                ConstValue::FloatingPoint { .. } => VariableType::FloatingPoint,
+                ConstValue::Character { .. } => VariableType::Char,
+            }),
+            Node::VariableReference { symbol, .. } => {
+                Some(self.symbols.variable_type(symbol.unwrap()))
+            }
+            Node::FunctionCall {
+                name,
+                symbol,
+                args,
+                span,
+            } => Some(self.function_call(name, symbol.unwrap(), args, *span)),
+            Node::BinaryOp {
+                left,
+                op,
+                right,
+                span,
+            } => {
+                let left = self.expression(left)?;
+                let right = self.expression(right)?;
+                self.binary_op(op, left, right, *span)
+            }
+            Node::UnaryOp { op, value, span } => {
+                let value = self.expression(value)?;
+                match op {
+                    UnaryOperation::Negate => match value {
+                        VariableType::Integer | VariableType::FloatingPoint => Some(value),
+                        _ => {
+                            self.error(
+                                "E0403",
+                                format!("cannot negate a value of type {}", type_name(value)),
+                                *span,
+                            );
+                            None
+                        }
+                    },
+                }
+            }
+            _ => unreachable!("{:?} is not an expression", node),
+        }
+    }
+    fn binary_op(
+        &mut self,
+        op: &BinaryOperation,
+        left: VariableType,
+        right: VariableType,
+        span: Span,
+    ) -> Option<VariableType> {
+        let result = match op {
+            BinaryOperation::Add
+            | BinaryOperation::Sub
+            | BinaryOperation::Mul
+            | BinaryOperation::Div
+            | BinaryOperation::Mod => match (left, right) {
+                (VariableType::Integer, VariableType::Integer) => Some(VariableType::Integer),
+                (VariableType::FloatingPoint, VariableType::FloatingPoint)
+                    if *op != BinaryOperation::Mod =>
+                {
+                    Some(VariableType::FloatingPoint)
+                }
+                _ => None,
+            },
+            BinaryOperation::Equal | BinaryOperation::NotEqual => {
+                (left == right && left != VariableType::Void).then_some(VariableType::Boolean)
+            }
+            BinaryOperation::Less
+            | BinaryOperation::LessEqual
+            | BinaryOperation::Greater
+            | BinaryOperation::GreaterEqual => match (left, right) {
+                (VariableType::Integer, VariableType::Integer)
+                | (VariableType::FloatingPoint, VariableType::FloatingPoint)
+                | (VariableType::Char, VariableType::Char) => Some(VariableType::Boolean),
+                _ => None,
+            },
+            BinaryOperation::And | BinaryOperation::Or => match (left, right) {
+                (VariableType::Boolean, VariableType::Boolean) => Some(VariableType::Boolean),
+                _ => None,
+            },
+        };
+        if result.is_none() {
+            self.error(
+                "E0404",
+                format!(
+                    "cannot apply '{}' to {} and {}",
+                    op.symbol(),
+                    type_name(left),
+                    type_name(right)
+                ),
+                span,
+            );
+        }
+        result
+    }
+    fn function_call(
+        &mut self,
+        name: &str,
+        symbol: SymbolId,
+        args: &[Node],
+        span: Span,
+    ) -> VariableType {
+        let arg_types: Vec<Option<VariableType>> =
+            args.iter().map(|arg| self.expression(arg)).collect();
+        let function = self.symbols.get(symbol);
+        let SymbolKind::Function {
+            params,
+            variadic,
+            return_type,
+        } = &function.kind
+        else {
+            unreachable!("{:?} is not a function", function)
+        };
+        let (params, variadic, return_type, declared) =
+            (params.clone(), *variadic, *return_type, function.span);
+
+        if args.len() < params.len() || (!variadic && args.len() > params.len()) {
+            let mut diagnostic = Diagnostic::error(
+                "E0406",
+                format!(
+                    "'{}' takes {} argument{} but {} {} given",
+                    name,
+                    params.len(),
+                    if params.len() == 1 { "" } else { "s" },
+                    args.len(),
+                    if args.len() == 1 { "was" } else { "were" }
+                ),
+                span,
+            );
+            if !declared.is_dummy() {
+                diagnostic = diagnostic.with_label(declared, "function declared here");
+            }
+            self.diagnostics.push(diagnostic);
+        }
+        // also covers the extra arguments of a variadic function, which have no parameter
+        for (arg, arg_type) in args.iter().zip(&arg_types) {
+            if *arg_type == Some(VariableType::Void) {
+                self.error(
+                    "E0410",
+                    "a void value cannot be passed as an argument".to_string(),
+                    arg.span(),
+                );
+            }
+        }
+        for ((arg, arg_type), param) in args.iter().zip(arg_types).zip(params) {
+            if let Some(arg_type) = arg_type {
+                if arg_type != param && arg_type != VariableType::Void {
+                    self.mismatch(param, arg_type, arg.span());
+                }
+            }
+        }
+        return_type
+    }
+    fn condition(&mut self, condition: &Node) {
+        if let Some(condition_type) = self.expression(condition) {
+            if condition_type != VariableType::Boolean {
+                self.mismatch(VariableType::Boolean, condition_type, condition.span());
+            }
+        }
+    }
+    fn block(&mut self, body: &[Node]) {
+        for node in body {
+            self.statement(node);
+        }
+    }
+    fn statement(&mut self, node: &Node) {
+        match node {
+            Node::Assign {
+                var_type, value, ..
+            } => {
+                if let Some(value_type) = self.expression(value) {
+                    if value_type != *var_type {
+                        self.mismatch(*var_type, value_type, value.span());
+                    }
+                }
+            }
+            Node::Reassign { symbol, value, .. } => {
+                let var_type = self.symbols.variable_type(symbol.unwrap());
+                if let Some(value_type) = self.expression(value) {
+                    if value_type != var_type {
+                        self.mismatch(var_type, value_type, value.span());
+                    }
+                }
+            }
+            Node::FunctionCall {
+                name,
+                symbol,
+                args,
+                span,
+            } => {
+                self.function_call(name, symbol.unwrap(), args, *span);
+            }
+            Node::Return { value, span } => match **value {
+                Node::Blank => {
+                    if self.return_type != VariableType::Void {
+                        self.error(
+                            "E0407",
+                            format!("expected a return value of type {}", type_name(self.return_type)),
+                            *span,
+                        );
+                    }
+                }
+                _ => {
+                    let Some(value_type) = self.expression(value) else {
+                        return;
+                    };
+                    if self.return_type == VariableType::Void {
+                        self.error(
+                            "E0408",
+                            "cannot return a value from a void function".to_string(),
+                            value.span(),
+                        );
+                    } else if value_type != self.return_type {
+                        self.mismatch(self.return_type, value_type, value.span());
+                    }
+                }
+            },
+            Node::If {
+                condition,
+                body,
+                else_body,
+                ..
+            } => {
+                self.condition(condition);
+                self.block(body);
+                self.block(else_body);
+            }
+            Node::While {
+                condition, body, ..
+            } => {
+                self.condition(condition);
+                self.block(body);
+            }
+            Node::Break { .. } | Node::Continue { .. } => {}
+            _ => unreachable!("{:?} is not a statement", node),
+        }
+    }
+    pub fn check(&mut self, program: &Node) -> Result<(), Vec<Diagnostic>> {
+        let Node::Program { body, .. } = program else {
+            unreachable!()
+        };
+        for function in body {
+            if let Node::Function {
+                name,
+                return_type,
+                body,
+                span,
+                ..
+            } = function
+            {
+                self.return_type = *return_type;
+                self.block(body);
+                if *return_type != VariableType::Void && !always_returns(body) {
+                    // the span of a function ends with its closing brace
+                    let end = Span::new(span.file_id, span.end - 1, span.end);
+                    self.error(
+                        "E0409",
+                        format!(
+                            "'{}' can reach its end without returning a value of type {}",
+                            name,
+                            type_name(*return_type)
+                        ),
+                        end,
+                    );
+                }
+            }
+        }
+
+        if self.diagnostics.is_empty() {
+            Ok(())
+        } else {
+            Err(self.diagnostics.clone())
+        }
+    }
+    pub fn check_instructions(program: &Node, symbols: &SymbolTable) -> Result<(), Vec<Diagnostic>> {
+        Checker::new(symbols).check(program)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+    use crate::resolver::Resolver;
+
+    fn check(source: &str) -> Result<(), Vec<Diagnostic>> {
+        let tokens = Lexer::new()
+            .lex_text(0, source.to_string())
+            .expect("lexing failed");
+        let mut program = Parser::parse_tokens(tokens).expect("parsing failed");
+        let symbols = Resolver::resolve_program(&mut program).expect("resolving failed");
+        Checker::check_instructions(&program, &symbols)
+    }
+    fn error_codes(source: &str) -> Vec<&'static str> {
+        match check(source) {
+            Ok(()) => vec![],
+            Err(diagnostics) => diagnostics.iter().map(|diagnostic| diagnostic.code).collect(),
+        }
+    }
+    fn message(source: &str) -> String {
+        let diagnostics = check(source).expect_err("checking passed");
+        diagnostics[0].message.clone()
+    }
+
+    #[test]
+    fn well_typed_programs() {
+        let source = r#"
+            fun half(float x) -> float { return x / 2.0; }
+            fun main() -> int {
+                int i <- 1 + 2 * 3;
+                i <- -i;
+                bool b <- i < 4 and 'a' != 'b' or not_used();
+                string s <- concat("a", "b");
+                printf("%d %f %s\n", i, half(1.5), s);
+                return i;
+            }
+            fun not_used() -> bool { return true; }
+        "#;
+        assert_eq!(error_codes(source), Vec::<&str>::new());
+    }
+
+    // E0402 and E0405 were undefined variables and functions, the resolver reports those now
+    #[test]
+    fn each_error_code() {
+        assert_eq!(error_codes("fun main() { int x <- \"a\"; }"), vec!["E0401"]);
+        assert_eq!(error_codes("fun main() { int x <- 1; x <- 1.5; }"), vec!["E0401"]);
+        assert_eq!(error_codes("fun main() { if 1 {} }"), vec!["E0401"]);
+        assert_eq!(error_codes("fun main() { bool b <- -true; }"), vec!["E0403"]);
+        assert_eq!(error_codes("fun main() { int x <- 1 + \"a\"; }"), vec!["E0404"]);
+        assert_eq!(error_codes("fun f(int a) {} fun main() { f(1, 2); }"), vec!["E0406"]);
+        assert_eq!(error_codes("fun main() { printf(); }"), vec!["E0406"]);
+        assert_eq!(error_codes("fun f() -> int { return; }"), vec!["E0407"]);
+        assert_eq!(error_codes("fun f() { return 1; }"), vec!["E0408"]);
+        assert_eq!(error_codes("fun f() -> int { put_i(1); }"), vec!["E0409"]);
+        assert_eq!(error_codes("fun g() {} fun main() { put_i(g()); }"), vec!["E0410"]);
+        assert_eq!(error_codes("fun g() {} fun main() { printf(\"%d\", g()); }"), vec!["E0410"]);
+    }
+
+    #[test]
+    fn messages() {
+        assert_eq!(
+            message("fun main() { int x <- \"a\"; }"),
+            "mismatched types, expected int but found string"
+        );
+        assert_eq!(message("fun main() { int x <- 1 + \"a\"; }"), "cannot apply '+' to int and string");
+        assert_eq!(message("fun main() { bool b <- 'a' and true; }"), "cannot apply 'and' to char and bool");
+        assert_eq!(
+            message("fun f(int a) {} fun main() { f(); }"),
+            "'f' takes 1 argument but 0 were given"
+        );
+        assert_eq!(
+            message("fun f() -> int { }"),
+            "'f' can reach its end without returning a value of type int"
+        );
+    }
+
+    #[test]
+    fn every_path_has_to_return() {
+        let returns = |body: &str| error_codes(&format!("fun f(bool b) -> int {{ {} }}", body));
+        assert_eq!(returns("return 1;"), Vec::<&str>::new());
+        assert_eq!(returns("if b { return 1; } else { return 2; }"), Vec::<&str>::new());
+        assert_eq!(returns("if b { return 1; } else if true { return 2; }"), vec!["E0409"]);
+        assert_eq!(returns("if b { return 1; }"), vec!["E0409"]);
+        // an endless loop can only be left through a return
+        assert_eq!(returns("while true { return 1; }"), Vec::<&str>::new());
+        assert_eq!(returns("while true { }"), Vec::<&str>::new());
+        assert_eq!(returns("while b { return 1; }"), vec!["E0409"]);
+        assert_eq!(returns("while true { if b { break; } }"), vec!["E0409"]);
+        // a break in a nested loop only leaves that loop
+        assert_eq!(returns("while true { while b { break; } }"), Vec::<&str>::new());
+    }
+}
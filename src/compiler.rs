@@ -1,333 +1,675 @@
-use crate::builder_dir::builder_gas::Builder;
-use crate::parser::{BinaryOperation, ConstValue, Node, VariableType};
+use crate::builder_dir::backend::{
+    imm, label, Backend, Condition, Memory, Opcode, Operand, Reg, WordSize, CL, EAX, ECX, EDX, RAX,
+    RBP, XMM0,
+};
+use crate::regalloc::{Allocation, Location, RegisterAllocator};
+use crate::ir::{BinOp, BlockId, CmpOp, Inst, IrFunction, IrProgram, Terminator, VReg, Value};
+use crate::parser::VariableType;
+use crate::prelude::add_prelude;
 use std::collections::HashMap;
 
-
-fn dword_reg(register: String) -> String{
-    match &*register {
-        "rax" => "eax".to_string(),
-        "rdi" => "edi".to_string(),
-        "rsi" => "esi".to_string(),
-        "rdx" => "edx".to_string(),
-        "rbx" => "ebx".to_string(),
-        "rcx" => "ecx".to_string(),
-        _ => unreachable!()
+// chars are compared unsigned, everything else signed
+fn condition(op: CmpOp, operand_type: VariableType) -> Condition {
+    let unsigned = operand_type == VariableType::Char;
+    match op {
+        CmpOp::Equal => Condition::Equal,
+        CmpOp::NotEqual => Condition::NotEqual,
+        CmpOp::Less if unsigned => Condition::Below,
+        CmpOp::LessEqual if unsigned => Condition::BelowEqual,
+        CmpOp::Greater if unsigned => Condition::Above,
+        CmpOp::GreaterEqual if unsigned => Condition::AboveEqual,
+        CmpOp::Less => Condition::Less,
+        CmpOp::LessEqual => Condition::LessEqual,
+        CmpOp::Greater => Condition::Greater,
+        CmpOp::GreaterEqual => Condition::GreaterEqual,
     }
 }
-fn word_reg(register: String) -> String{
-    match &*register {
-        "rax" => "ax".to_string(),
-        "rdi" => unreachable!(),
-        "rsi" => unreachable!(),
-        "rdx" => "dx".to_string(),
-        "rbx" => "bx".to_string(),
-        "rcx" => "cx".to_string(),
-        _ => unreachable!()
+// how many bytes a value of the type takes in a register or stack slot
+fn word_size(var_type: VariableType) -> WordSize {
+    match var_type {
+        VariableType::String | VariableType::FloatingPoint => WordSize::Qword,
+        VariableType::Boolean | VariableType::Char => WordSize::Byte,
+        _ => WordSize::Dword,
     }
 }
-pub struct Compiler {
-    ast: Node,
-    pub builder: Builder,
-    local_variables: HashMap<String, usize>,
-    string_literal_count: usize,
-    unused_registers: Vec<String>
+fn string_label(index: usize) -> String {
+    format!("local_string_{}", index)
+}
+fn float_label(index: usize) -> String {
+    format!("local_float_{}", index)
 }
 
-impl Compiler {
-    pub fn new(ast: Node) -> Self {
-        let mut new = Self {
-            ast,
-            builder: Builder::new_program("main"),
-            local_variables: HashMap::new(),
-            string_literal_count: 0,
-            unused_registers: vec![
-                "rax".to_string(),
-                "rdi".to_string(),
-                "rsi".to_string(),
-                "rdx".to_string(),
-                "rbx".to_string(),
-                "rcx".to_string(),
-            ]
-        };
-        new.unused_registers.reverse();
-        new
+// generates assembly for the IR through one of the backends, locals live in stack slots
+// and virtual registers wherever the register allocator put them
+pub struct Compiler<B: Backend> {
+    program: IrProgram,
+    pub builder: B,
+    // builder local of every IR local of the current function
+    local_slots: Vec<usize>,
+    // register or stack slot of every virtual register of the current function
+    vreg_operands: Vec<Operand>,
+    // caller saved registers to keep across each call, by (block, instruction)
+    call_saves: HashMap<(usize, usize), Vec<Reg>>,
+    block_labels: Vec<String>,
+    return_label: String,
+}
+
+impl<B: Backend> Compiler<B> {
+    pub fn new(program: IrProgram) -> Self {
+        Self {
+            program,
+            builder: B::new_program("main"),
+            local_slots: vec![],
+            vreg_operands: vec![],
+            call_saves: HashMap::new(),
+            block_labels: vec![],
+            return_label: "".to_string(),
+        }
     }
-    pub fn bin_op(&mut self, left: Node, op: BinaryOperation, right: Node) -> String{
-        let mut last_register = "".to_string();
-        let mut last_value = ConstValue::Integer {
-            value: 0
-        };
-        match right {
-            Node::Const { value_type } => {
-                last_value = value_type;
+
+    fn operand(&self, value: &Value) -> Operand {
+        match value {
+            Value::VReg(vreg) => self.vreg_operands[vreg.0].clone(),
+            Value::Int(value) => imm(*value),
+            Value::Bool(value) => imm(*value),
+            Value::Char(value) => imm(*value),
+            Value::Str(index) => label(&string_label(*index)),
+            Value::Float(index) => Operand::Data {
+                size: WordSize::Qword,
+                label: float_label(*index),
+            },
+        }
+    }
+    // floats never get a general purpose register, they go through xmm registers
+    fn load_float(&mut self, value: &Value, register: Reg) {
+        let operand = self.operand(value);
+        if operand != Operand::Register(register) {
+            self.builder.movsd(register, operand);
+        }
+    }
+    // puts the value into the part of register that fits its type
+    fn load(&mut self, function: &IrFunction, value: &Value, register: Reg) -> Reg {
+        let register = register.with_size(word_size(function.value_type(value)));
+        let operand = self.operand(value);
+        if operand != Operand::Register(register) {
+            self.builder.mov(register, operand);
+        }
+        register
+    }
+    // the register the value already is in, or scratch after loading it there
+    fn in_register(&mut self, function: &IrFunction, value: &Value, scratch: Reg) -> Reg {
+        match self.operand(value) {
+            Operand::Register(register) => register,
+            _ => self.load(function, value, scratch),
+        }
+    }
+    // the register to compute dest in, scratch when dest was spilled
+    fn dest_register(&self, function: &IrFunction, dest: VReg, scratch: Reg) -> Reg {
+        match self.vreg_operands[dest.0] {
+            Operand::Register(register) => register,
+            _ => scratch.with_size(word_size(function.vregs[dest.0])),
+        }
+    }
+    fn store(&mut self, dest: VReg, register: Reg) {
+        let location = self.vreg_operands[dest.0].clone();
+        if location != Operand::Register(register) {
+            self.builder.mov(location, register);
+        }
+    }
+
+    // bools and chars are zero extended, like C promotes them for printf
+    fn load_argument(&mut self, function: &IrFunction, value: &Value, register: Reg) {
+        if word_size(function.value_type(value)) != WordSize::Byte {
+            self.load(function, value, register);
+            return;
+        }
+        let register = register.with_size(WordSize::Dword);
+        match self.operand(value) {
+            Operand::Immediate(value) => self.builder.mov(register, imm(value)),
+            operand => self.builder.movzx(register, operand),
+        }
+    }
+    // the register every argument of the given types is passed in, None for the ones
+    // passed on the stack, floats and the rest each fill their own registers
+    fn argument_registers(&self, types: impl Iterator<Item = VariableType>) -> Vec<Option<Reg>> {
+        let mut registers = self.builder.func_args_order().into_iter();
+        let mut float_registers = self.builder.float_args_order().into_iter();
+        types
+            .map(|arg_type| match arg_type {
+                VariableType::FloatingPoint => float_registers.next(),
+                _ => registers.next(),
+            })
+            .collect()
+    }
+    fn call(
+        &mut self,
+        function: &IrFunction,
+        dest: Option<VReg>,
+        name: &str,
+        variadic: bool,
+        args: &[Value],
+        saves: &[Reg],
+    ) {
+        for register in saves {
+            self.builder.push(*register);
+        }
+        let registers = self.argument_registers(args.iter().map(|arg| function.value_type(arg)));
+        // the arguments that do not fit in registers are pushed right to left, keeping rsp 16 byte aligned
+        let stack_args: Vec<&Value> = args
+            .iter()
+            .zip(&registers)
+            .filter_map(|(arg, register)| register.is_none().then_some(arg))
+            .collect();
+        let stack_size = stack_args.len() as u32 * 8 + self.builder.align_stack_for_call(stack_args.len());
+        for arg in stack_args.into_iter().rev() {
+            self.load_argument(function, arg, RAX);
+            self.builder.push(RAX);
+        }
+        for (register, arg) in registers.iter().zip(args) {
+            match register {
+                Some(register) if register.is_xmm() => self.load_float(arg, *register),
+                Some(register) => self.load_argument(function, arg, *register),
+                None => {}
             }
-            Node::BinaryOp { left, op, right } => {
-                last_register = self.bin_op(*left, op, *right);
+        }
+        if variadic {
+            // al tells a variadic function how many xmm registers hold arguments
+            let floats = registers.iter().flatten().filter(|register| register.is_xmm()).count();
+            self.builder.mov(EAX, imm(floats as i128));
+        }
+        self.builder.call(name);
+        if stack_size > 0 {
+            self.builder.release_stack(stack_size);
+        }
+        for register in saves.iter().rev() {
+            self.builder.pop(*register);
+        }
+        match dest {
+            Some(dest) if function.vregs[dest.0] == VariableType::FloatingPoint => {
+                let location = self.vreg_operands[dest.0].clone();
+                self.builder.movsd(location, XMM0);
             }
-            _ => unreachable!()
+            Some(dest) => self.store(dest, RAX.with_size(word_size(function.vregs[dest.0]))),
+            None => {}
         }
-        match left {
-            Node::Const { value_type } => {
-                if !last_register.is_empty() {
-                    unimplemented!()
-                } else {
-                    match value_type {
-                        ConstValue::String { .. } => {
-                            match last_value {
-                                ConstValue::String { .. } => {
-                                    unimplemented!()
-                                }
-                                ConstValue::Integer { .. } => {
-                                    unimplemented!()
-                                }
-                                ConstValue::Boolean { .. } => {
-                                    unimplemented!()
-                                }
-                                ConstValue::FloatingPoint { .. } => {
-                                    unimplemented!()
-                                }
-                            }
-                        }
-                        ConstValue::Integer { value } => {
-                            let left_value = value;
-                            match last_value {
-                                ConstValue::String { .. } => {
-                                    unimplemented!()
-                                }
-                                ConstValue::Integer { value } => {
-                                    let eval = left_value + value;
-                                    let register = self.unused_registers.pop().unwrap();
-                                    self.builder.mov(&*register.clone(), &*eval.to_string());
-                                    return register
-                                }
-                                ConstValue::Boolean { .. } => {
-                                    unimplemented!()
-                                }
-                                ConstValue::FloatingPoint { .. } => {
-                                    unimplemented!()
-                                }
-                            }
-                        }
-                        ConstValue::Boolean { .. } => {
-                            match last_value {
-                                ConstValue::String { .. } => {
-                                    unimplemented!()
-                                }
-                                ConstValue::Integer { .. } => {
-                                    unimplemented!()
-                                }
-                                ConstValue::Boolean { .. } => {
-                                    unimplemented!()
-                                }
-                                ConstValue::FloatingPoint { .. } => {
-                                    unimplemented!()
-                                }
-                            }
-                        }
-                        ConstValue::FloatingPoint { .. } => {
-                            match last_value {
-                                ConstValue::String { .. } => {
-                                    unimplemented!()
-                                }
-                                ConstValue::Integer { .. } => {
-                                    unimplemented!()
-                                }
-                                ConstValue::Boolean { .. } => {
-                                    unimplemented!()
-                                }
-                                ConstValue::FloatingPoint { .. } => {
-                                    unimplemented!()
-                                }
-                            }
-                        }
-                    }
-                }
+    }
+    // float values all live in memory, so xmm0 is the only register the result passes through
+    fn float_binary(&mut self, dest: VReg, op: BinOp, left: &Value, right: &Value) {
+        self.load_float(left, XMM0);
+        let op = match op {
+            BinOp::Add => Opcode::Addsd,
+            BinOp::Sub => Opcode::Subsd,
+            BinOp::Mul => Opcode::Mulsd,
+            BinOp::Div => Opcode::Divsd,
+            BinOp::Mod => unreachable!("the checker rejects float modulo"),
+        };
+        let right = self.operand(right);
+        self.builder.float_op(op, XMM0, right);
+        let location = self.vreg_operands[dest.0].clone();
+        self.builder.movsd(location, XMM0);
+    }
+    // ucomisd sets the flags of an unsigned comparison, and all of ZF, PF and CF when either
+    // side is NaN, so only above and above or equal are false for NaN without another check
+    fn float_compare(&mut self, function: &IrFunction, dest: VReg, op: CmpOp, left: &Value, right: &Value) {
+        let (left, right) = match op {
+            CmpOp::Less | CmpOp::LessEqual => (right, left),
+            _ => (left, right),
+        };
+        self.load_float(left, XMM0);
+        let right = self.operand(right);
+        self.builder.ucomisd(XMM0, right);
+        let register = self.dest_register(function, dest, RAX);
+        match op {
+            CmpOp::Equal => {
+                self.builder.set(Condition::Equal, register);
+                self.builder.set(Condition::NoParity, CL);
+                self.builder.and(register, CL);
             }
-            Node::BinaryOp { left, op, right } => {
-                last_register = self.bin_op(*left, op, *right);
+            CmpOp::NotEqual => {
+                self.builder.set(Condition::NotEqual, register);
+                self.builder.set(Condition::Parity, CL);
+                self.builder.or(register, CL);
             }
-            _ => unreachable!()
+            CmpOp::Greater | CmpOp::Less => self.builder.set(Condition::Above, register),
+            CmpOp::GreaterEqual | CmpOp::LessEqual => self.builder.set(Condition::AboveEqual, register),
         }
-        "".to_string()
+        self.store(dest, register);
     }
-    pub fn assignment(&mut self, name: String, var_type: VariableType, value: Box<Node>) {
-        let var_id = self.local_variables.len();
-        self.local_variables.insert(name, var_id);
-        match var_type {
-            VariableType::String => {
-                unimplemented!()
+    // idiv divides edx:eax, neither is ever allocated so nothing live has to move out of the way
+    fn division(&mut self, function: &IrFunction, dest: VReg, op: BinOp, left: &Value, right: &Value) {
+        self.load(function, left, RAX);
+        let divisor = match self.operand(right) {
+            Operand::Immediate(_) => Operand::Register(self.load(function, right, ECX)),
+            divisor => divisor,
+        };
+        self.builder.idiv(divisor);
+        let result = if op == BinOp::Div { EAX } else { EDX };
+        self.store(dest, result);
+    }
+    // position is the (block, instruction) of inst
+    fn instruction(&mut self, function: &IrFunction, inst: &Inst, position: (usize, usize)) {
+        match inst {
+            Inst::Binary {
+                dest,
+                op,
+                left,
+                right,
+            } => {
+                if function.value_type(left) == VariableType::FloatingPoint {
+                    return self.float_binary(*dest, *op, left, right);
+                }
+                if let BinOp::Div | BinOp::Mod = op {
+                    return self.division(function, *dest, *op, left, right);
+                }
+                // dest never shares a register with the operands, they are still live here
+                let register = self.dest_register(function, *dest, RAX);
+                self.load(function, left, register);
+                let right = self.operand(right);
+                match op {
+                    BinOp::Add => self.builder.add(register, right),
+                    BinOp::Sub => self.builder.sub(register, right),
+                    BinOp::Mul => self.builder.imul(register, right),
+                    BinOp::Div | BinOp::Mod => unreachable!(),
+                }
+                self.store(*dest, register);
             }
-            VariableType::Integer => {
-                let mut int_value = 0;
-                match *value {
-                    Node::Expr { value } => match *value {
-                        Node::Const { value_type } => match value_type {
-                            ConstValue::Integer { value } => {
-                                self.builder.new_local_dword(value as u32);
-                            },
-                            _ => {
-                                panic!("dhd")
-                            }
-                        },
-                        Node::BinaryOp { left, op, right } => {
-                            let register = self.bin_op(*left, op, *right);
-                            self.builder.local_word_assign(4, "dword", &*dword_reg(register.clone()));
-                            self.unused_registers.push(register);
-                        }
-                        _ => unreachable!(),
-                    },
-                    _ => unreachable!(),
+            Inst::Neg { dest, value } => {
+                let register = self.dest_register(function, *dest, RAX);
+                self.load(function, value, register);
+                self.builder.neg(register);
+                self.store(*dest, register);
+            }
+            Inst::Compare {
+                dest,
+                op,
+                left,
+                right,
+            } => {
+                if function.value_type(left) == VariableType::FloatingPoint {
+                    return self.float_compare(function, *dest, *op, left, right);
                 }
+                let register = self.in_register(function, left, RAX);
+                let right = self.operand(right);
+                self.builder.cmp(register, right);
+                let dest_register = self.dest_register(function, *dest, RAX);
+                self.builder.set(condition(*op, function.value_type(left)), dest_register);
+                self.store(*dest, dest_register);
             }
-            VariableType::Boolean => {
-                unimplemented!()
+            Inst::Load { dest, local } => {
+                let register = self.dest_register(function, *dest, RAX);
+                let slot = self.builder.local_operand(self.local_slots[local.0]);
+                self.builder.mov(register, slot);
+                self.store(*dest, register);
             }
-            VariableType::FloatingPoint => {
-                unimplemented!()
+            Inst::Store { local, value } => {
+                let slot = self.builder.local_operand(self.local_slots[local.0]);
+                match self.operand(value) {
+                    Operand::Immediate(value) => self.builder.mov(slot, imm(value)),
+                    _ => {
+                        let register = self.in_register(function, value, RAX);
+                        self.builder.mov(slot, register);
+                    }
+                }
             }
-            VariableType::Void => {
-                unreachable!()
+            Inst::Call {
+                dest,
+                function: name,
+                args,
+                variadic,
+            } => {
+                let saves = self.call_saves.remove(&position).unwrap_or_default();
+                self.call(function, *dest, name, *variadic, args, &saves)
             }
         }
     }
-    pub fn function(
-        &mut self,
-        name: String,
-        _return_type: VariableType,
-        _args: Vec<Box<Node>>,
-        body: Vec<Box<Node>>,
-    ) {
-        self.builder.open_function(&*name);
-        for part in body {
-            self.single(*part);
-        }
-        self.builder.close_function();
-        self.local_variables = HashMap::new();
-    }
-    pub fn expr_handler(&mut self, expr_value: Node) -> String {
-        match expr_value {
-            Node::Const { value_type } => match value_type {
-                ConstValue::String { value } => {
-                    self.string_literal_count += 1;
-                    self.builder.new_string_literal(
-                        &*format!("local_string_{}", self.string_literal_count),
-                        &*value,
-                    );
-                    format!("local_string_{}", self.string_literal_count)
-                }
-                ConstValue::Integer { value } => value.to_string(),
-                ConstValue::Boolean { value } => {
-                    unimplemented!("{}", value)
-                }
-                ConstValue::FloatingPoint { value } => {
-                    unimplemented!("{}", value)
+    // `next` is the block placed right after this one, jumping there is left out
+    fn terminator(&mut self, function: &IrFunction, terminator: &Terminator, next: Option<BlockId>) {
+        match terminator {
+            Terminator::Jump(target) => {
+                if Some(*target) != next {
+                    let target = self.block_labels[target.0].clone();
+                    self.builder.jmp(&target);
                 }
-            },
-            Node::FunctionCall { .. } => {
-                unimplemented!()
             }
-            Node::VariableReference { name } => {
-                let variable_id = self.local_variables.get(&*name).unwrap().clone();
-                let (_res, var_size, var_loc) = self.builder.get_local_word_size_and_offset(variable_id);
-                match var_size {
-                    2 => {
-                        format!("word [rsp - {}]", var_loc)
+            Terminator::Branch {
+                op,
+                left,
+                right,
+                then_block,
+                else_block,
+            } => {
+                let operand_type = function.value_type(left);
+                let register = self.in_register(function, left, RAX);
+                let right = self.operand(right);
+                self.builder.cmp(register, right);
+                let then_label = self.block_labels[then_block.0].clone();
+                let else_label = self.block_labels[else_block.0].clone();
+                if Some(*then_block) == next {
+                    self.builder.jump_if(condition(op.inverted(), operand_type), &else_label);
+                } else {
+                    self.builder.jump_if(condition(*op, operand_type), &then_label);
+                    if Some(*else_block) != next {
+                        self.builder.jmp(&else_label);
                     }
-                    4 => {
-                        format!("dword [rsp - {}]", var_loc)
+                }
+            }
+            Terminator::Return(value) => {
+                match value {
+                    Some(value) if function.value_type(value) == VariableType::FloatingPoint => {
+                        self.load_float(value, XMM0)
                     }
-                    8 => {
-                        format!("qword [rsp - {}]", var_loc)
+                    Some(value) => {
+                        self.load(function, value, RAX);
                     }
-
-                    _ => unreachable!()
+                    None => {}
+                }
+                if next.is_some() {
+                    let return_label = self.return_label.clone();
+                    self.builder.jmp(&return_label);
                 }
             }
-            Node::BinaryOp { .. } => {
-                unimplemented!()
-            }
-            _ => {
-                unreachable!()
-            }
+            Terminator::Unreachable => {}
         }
     }
+    fn function(&mut self, function: &IrFunction) {
+        if function.name != "main" {
+            self.builder.global_add(&function.name);
+        }
+        self.builder.open_function(&function.name);
+        self.return_label = self.builder.new_label("return");
+        self.block_labels = (0..function.blocks.len())
+            .map(|id| self.builder.new_label(&format!("bb{}", id)))
+            .collect();
+        self.local_slots = function
+            .locals
+            .iter()
+            .map(|local| self.builder.new_local(word_size(local.var_type)))
+            .collect();
+        let Allocation {
+            locations,
+            callee_saved,
+            call_saves,
+        } = RegisterAllocator::allocate_function(function);
+        self.vreg_operands = locations
+            .iter()
+            .zip(&function.vregs)
+            .map(|(location, var_type)| match location {
+                Location::Register(register) => Reg::new(*register, word_size(*var_type)).into(),
+                Location::Stack => {
+                    let slot = self.builder.new_local(word_size(*var_type));
+                    self.builder.local_operand(slot)
+                }
+            })
+            .collect();
+        self.call_saves = call_saves
+            .into_iter()
+            .map(|(position, registers)| {
+                let registers = registers
+                    .into_iter()
+                    .map(|register| Reg::new(register, WordSize::Qword))
+                    .collect();
+                (position, registers)
+            })
+            .collect();
+        // the caller expects the callee saved registers back the way it left them
+        let callee_saved: Vec<(Reg, Operand)> = callee_saved
+            .into_iter()
+            .map(|register| {
+                let slot = self.builder.new_local(WordSize::Qword);
+                (Reg::new(register, WordSize::Qword), self.builder.local_operand(slot))
+            })
+            .collect();
+        for (register, slot) in &callee_saved {
+            self.builder.mov(slot.clone(), *register);
+        }
 
-    pub fn function_call(&mut self, function_name: String, args: Vec<Box<Node>>) {
-        let mut new_args = vec![];
-        for arg in args {
-            match *arg {
-                Node::Expr { value } => {
-                    new_args.push(self.expr_handler(*value))
-                },
-
-                _ => {
-                    unreachable!()
+        // copies the parameters from their System V registers or stack slots into their locals
+        let param_types = function.params.iter().map(|local| function.locals[local.0].var_type);
+        let registers = self.argument_registers(param_types);
+        let mut stack_index = 0;
+        for (local, register) in function.params.iter().zip(registers) {
+            let size = word_size(function.locals[local.0].var_type);
+            let slot = self.builder.local_operand(self.local_slots[local.0]);
+            match register {
+                Some(register) if register.is_xmm() => self.builder.movsd(slot, register),
+                Some(register) => self.builder.mov(slot, register.with_size(size)),
+                None => {
+                    let stack_slot = Memory {
+                        size,
+                        base: RBP,
+                        offset: 16 + 8 * stack_index,
+                    };
+                    stack_index += 1;
+                    self.builder.mov(RAX.with_size(size), stack_slot);
+                    self.builder.mov(slot, RAX.with_size(size));
                 }
             }
         }
-        let mut len = -1;
-        for argument in new_args {
-            len += 1;
-            let register = &*self
-                .builder
-                .func_args_order
-                .get(len as usize)
-                .unwrap()
-                .clone();
-            self.builder.mov(register, &*argument);
+
+        for (id, block) in function.blocks.iter().enumerate() {
+            if id != 0 {
+                let block_label = self.block_labels[id].clone();
+                self.builder.label(&block_label);
+            }
+            for (index, inst) in block.insts.iter().enumerate() {
+                self.instruction(function, inst, (id, index));
+            }
+            let next = (id + 1 < function.blocks.len()).then_some(BlockId(id + 1));
+            self.terminator(function, &block.terminator, next);
+        }
+        let return_label = self.return_label.clone();
+        self.builder.label(&return_label);
+        if function.name == "main" && function.return_type == VariableType::Void {
+            // a void main still hands the C runtime an exit code of 0
+            self.builder.mov(EAX, imm(0));
+        }
+        for (register, slot) in callee_saved {
+            self.builder.mov(register, slot);
+        }
+        self.builder.close_function();
+    }
+    pub fn run(&mut self) -> String {
+        add_prelude(&mut self.builder);
+        for (index, string) in self.program.strings.iter().enumerate() {
+            self.builder.new_string_literal(&string_label(index), string);
+        }
+        for (index, float) in self.program.floats.iter().enumerate() {
+            self.builder.new_float_literal(&float_label(index), *float);
         }
+        let functions = std::mem::take(&mut self.program.functions);
+        for function in &functions {
+            self.function(function);
+        }
+        self.builder.build_no_start()
+    }
+    pub fn compile(program: IrProgram) -> String {
+        Self::new(program).run()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder_dir::backend::{Instr, TextItem, R9D, RSP, XMM7};
+    use crate::builder_dir::builder_gas::Builder;
+    use crate::checker::Checker;
+    use crate::fold::Folder;
+    use crate::lexer::Lexer;
+    use crate::lower::Lowerer;
+    use crate::parser::Parser;
+    use crate::resolver::Resolver;
 
-        self.builder.call(&*function_name);
+    // the instructions generated for one function of the program
+    fn body(source: &str, name: &str) -> Vec<Instr> {
+        let tokens = Lexer::new()
+            .lex_text(0, source.to_string())
+            .expect("lexing failed");
+        let mut program = Parser::parse_tokens(tokens).expect("parsing failed");
+        let symbols = Resolver::resolve_program(&mut program).expect("resolving failed");
+        Checker::check_instructions(&program, &symbols).expect("checking failed");
+        Folder::fold_constants(&mut program).expect("folding failed");
+        let program = Lowerer::lower_program(program, &symbols).expect("lowering failed");
+        let mut compiler = Compiler::<Builder>::new(program);
+        compiler.run();
+        compiler
+            .builder
+            .state()
+            .text_items
+            .iter()
+            .find_map(|item| match item {
+                TextItem::Function(function) if function.name == name => Some(function.body.clone()),
+                _ => None,
+            })
+            .expect("no such function")
     }
 
-    fn return_function(&mut self, value: Box<Node>) {
-        match *value {
-            Node::Expr { value } =>  {
-                let ret_val = &*self.expr_handler(*value).clone();
-                self.builder.mov("rax", ret_val);
-            },
-            _ => {
-                unreachable!()
-            }
-        }
+    fn op(opcode: Opcode, operands: Vec<Operand>) -> Instr {
+        Instr::Op(opcode, operands)
     }
 
-    pub fn single(&mut self, node: Node) {
-        match node {
-            Node::Assign {
-                name,
-                var_type,
-                value,
-            } => self.assignment(name, var_type, value),
-            Node::Function {
-                name,
-                return_type,
-                args,
-                body,
-            } => {
-                self.function(name, return_type, args, body);
-            }
-            Node::FunctionCall { name, args } => self.function_call(name, args),
-            Node::Return { value } => self.return_function(value),
-            _ => {
-                panic!("what {:?}", node)
-            }
+    #[test]
+    fn division_sign_extends_into_edx() {
+        let body = body("fun f(int a, int b) -> int { return a / b + a % 7; }", "f");
+        let divisions: Vec<usize> = (0..body.len())
+            .filter(|index| matches!(body[*index], Instr::Op(Opcode::Idiv, _)))
+            .collect();
+        assert_eq!(divisions.len(), 2);
+        for index in divisions {
+            assert_eq!(body[index - 1], op(Opcode::Cdq, vec![]));
         }
+        // the dividend goes into eax, idiv has no immediate form and the remainder is in edx
+        let modulo = body
+            .windows(5)
+            .find(|window| window[3] == op(Opcode::Idiv, vec![ECX.into()]))
+            .expect("no division by ecx");
+        assert!(matches!(&modulo[0], Instr::Op(Opcode::Mov, operands) if operands[0] == EAX.into()));
+        assert_eq!(modulo[1], op(Opcode::Mov, vec![ECX.into(), imm(7)]));
+        assert!(matches!(&modulo[4], Instr::Op(Opcode::Mov, operands) if operands[1] == EDX.into()));
     }
-    pub fn run(&mut self) -> String {
-        self.builder.extern_add("printf");
-        match self.ast.clone() {
-            Node::Program { body } => {
-                for part in body {
-                    self.single(*part)
+
+    // the operands of the first cmp followed by a set, and the condition and operand of that set
+    fn compare_and_set(body: &[Instr]) -> (Vec<Operand>, Condition, Operand) {
+        body.windows(2)
+            .find_map(|window| match window {
+                [Instr::Op(Opcode::Cmp, compare), Instr::Op(Opcode::Set(condition), set)] => {
+                    Some((compare.clone(), *condition, set[0].clone()))
                 }
-            }
-            _ => {}
-        }
+                _ => None,
+            })
+            .expect("no cmp followed by set")
+    }
 
-        self.builder.build_no_start()
+    #[test]
+    fn comparisons_set_a_byte() {
+        let body = body("fun f(int a, int b) -> bool { bool c <- a < b; return c; }", "f");
+        let (compared, condition, destination) = compare_and_set(&body);
+        assert!(compared.iter().all(|operand| operand.size() == Some(WordSize::Dword)));
+        assert_eq!(condition, Condition::Less);
+        assert_eq!(destination.size(), Some(WordSize::Byte));
+    }
+
+    // NaN sets the parity flag, and compares unequal to everything
+    #[test]
+    fn float_equality_checks_parity() {
+        let not_equal = body("fun f(float x, float y) -> bool { bool c <- x != y; return c; }", "f");
+        let compare = not_equal
+            .iter()
+            .position(|instr| matches!(instr, Instr::Op(Opcode::Ucomisd, operands) if operands[0] == XMM0.into()))
+            .expect("no ucomisd");
+        let Instr::Op(Opcode::Set(Condition::NotEqual), result) = &not_equal[compare + 1] else {
+            panic!("no setne after ucomisd");
+        };
+        assert_eq!(not_equal[compare + 2], op(Opcode::Set(Condition::Parity), vec![CL.into()]));
+        assert_eq!(not_equal[compare + 3], op(Opcode::Or, vec![result[0].clone(), CL.into()]));
+
+        let equal = body("fun f(float x, float y) -> bool { bool c <- x == y; return c; }", "f");
+        let compare = equal
+            .iter()
+            .position(|instr| matches!(instr, Instr::Op(Opcode::Ucomisd, _)))
+            .expect("no ucomisd");
+        let Instr::Op(Opcode::Set(Condition::Equal), result) = &equal[compare + 1] else {
+            panic!("no sete after ucomisd");
+        };
+        assert_eq!(equal[compare + 2], op(Opcode::Set(Condition::NoParity), vec![CL.into()]));
+        assert_eq!(equal[compare + 3], op(Opcode::And, vec![result[0].clone(), CL.into()]));
+    }
+
+    #[test]
+    fn floats_past_xmm7_go_on_the_stack() {
+        let source = "fun h(float a, float b, float c, float d, float e, float f, float g, float i, float j) -> float { return j; } \
+                      fun f() -> float { return h(1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0, 9.0); }";
+        let caller = body(source, "f");
+        let call = caller.iter().position(|instr| *instr == Instr::Call("h".to_string())).unwrap();
+        let float = |index: usize| Operand::Data {
+            size: WordSize::Qword,
+            label: float_label(index),
+        };
+        // one pushed qword, padded to keep rsp 16 byte aligned at the call
+        let expected = [
+            op(Opcode::Sub, vec![RSP.into(), imm(8)]),
+            op(Opcode::Mov, vec![RAX.into(), float(8)]),
+            op(Opcode::Push, vec![RAX.into()]),
+        ];
+        assert_eq!(caller[call - 11..call - 8], expected);
+        assert_eq!(caller[call - 1], op(Opcode::Movsd, vec![XMM7.into(), float(7)]));
+        assert_eq!(caller[call + 1], op(Opcode::Add, vec![RSP.into(), imm(16)]));
+
+        let callee = body(source, "h");
+        let ninth = Memory {
+            size: WordSize::Qword,
+            base: RBP,
+            offset: 16,
+        };
+        assert!(callee.contains(&op(Opcode::Mov, vec![RAX.into(), ninth.into()])));
     }
-    pub fn compile(ast: Node) -> String {
-        Compiler::new(ast).run()
+
+    #[test]
+    fn ints_past_r9_go_on_the_stack() {
+        let source = "fun g(int a, int b, int c, int d, int e, int f, int h) -> int { return h; } \
+                      fun f() -> int { return g(1, 2, 3, 4, 5, 6, 7); }";
+        let caller = body(source, "f");
+        let call = caller.iter().position(|instr| *instr == Instr::Call("g".to_string())).unwrap();
+        assert_eq!(caller[call - 8..call - 6], [
+            op(Opcode::Mov, vec![EAX.into(), imm(7)]),
+            op(Opcode::Push, vec![RAX.into()]),
+        ]);
+        assert_eq!(caller[call - 1], op(Opcode::Mov, vec![R9D.into(), imm(6)]));
+        assert_eq!(caller[call + 1], op(Opcode::Add, vec![RSP.into(), imm(16)]));
+
+        let callee = body(source, "g");
+        let seventh = Memory {
+            size: WordSize::Dword,
+            base: RBP,
+            offset: 16,
+        };
+        assert!(callee.contains(&op(Opcode::Mov, vec![EAX.into(), seventh.into()])));
+    }
+
+    // al holds how many vector registers a variadic function has to look at
+    #[test]
+    fn variadic_calls_count_xmm_arguments() {
+        let printf = body("fun f(float x) { printf(\"%f %d\\n\", x, 1); }", "f");
+        let call = printf.iter().position(|instr| *instr == Instr::Call("printf".to_string())).unwrap();
+        assert_eq!(printf[call - 1], op(Opcode::Mov, vec![EAX.into(), imm(1)]));
+    }
+
+    // chars are bytes from 0 to 255, so they compare unsigned
+    #[test]
+    fn chars_compare_unsigned() {
+        let value = body("fun f(char a, char b) -> bool { bool c <- a < b; return c; }", "f");
+        let (compared, condition, _) = compare_and_set(&value);
+        assert!(compared.iter().all(|operand| operand.size() == Some(WordSize::Byte)));
+        assert_eq!(condition, Condition::Below);
+
+        let branch = body("fun f(char a, char b) { if a > b { put_i(1); } }", "f");
+        let jumps: Vec<Condition> = branch
+            .iter()
+            .filter_map(|instr| match instr {
+                Instr::Jump(condition, _) => *condition,
+                _ => None,
+            })
+            .collect();
+        // the then block comes next, so the branch jumps over it when a is not above b
+        assert_eq!(jumps, [Condition::BelowEqual]);
     }
 }
@@ -0,0 +1,229 @@
+use std::ffi::OsString;
+use std::io::IsTerminal;
+
+use crate::source_map::{SourceMap, Span};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+    Error,
+}
+
+// a secondary span shown next to the primary one, e.g. "scope opened here"
+#[derive(Debug, Clone, PartialEq)]
+pub struct Label {
+    pub span: Span,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub code: &'static str,
+    pub message: String,
+    pub span: Span,
+    pub labels: Vec<Label>,
+    pub notes: Vec<String>,
+}
+
+impl Diagnostic {
+    pub fn error(code: &'static str, message: String, span: Span) -> Self {
+        Self {
+            severity: Severity::Error,
+            code,
+            message,
+            span,
+            labels: vec![],
+            notes: vec![],
+        }
+    }
+    pub fn with_label(mut self, span: Span, message: &str) -> Self {
+        self.labels.push(Label {
+            span,
+            message: message.to_string(),
+        });
+        self
+    }
+    pub fn with_note(mut self, note: &str) -> Self {
+        self.notes.push(note.to_string());
+        self
+    }
+}
+
+const RED: &str = "\x1b[1;31m";
+const BLUE: &str = "\x1b[1;34m";
+const BOLD: &str = "\x1b[1m";
+const RESET: &str = "\x1b[0m";
+
+// colour only on a terminal and when NO_COLOR is not set, whatever its value
+fn use_colour(terminal: bool, no_color: Option<OsString>) -> bool {
+    terminal && no_color.is_none()
+}
+
+// renders diagnostics rustc style, with the offending source lines and a marker under each span
+pub struct Renderer<'a> {
+    source_map: &'a SourceMap,
+    colour: bool,
+}
+
+impl<'a> Renderer<'a> {
+    pub fn new(source_map: &'a SourceMap, colour: bool) -> Self {
+        Self { source_map, colour }
+    }
+    pub fn for_stderr(source_map: &'a SourceMap) -> Self {
+        let colour = use_colour(std::io::stderr().is_terminal(), std::env::var_os("NO_COLOR"));
+        Self::new(source_map, colour)
+    }
+    fn paint(&self, colour: &str, text: &str) -> String {
+        if self.colour {
+            format!("{}{}{}", colour, text, RESET)
+        } else {
+            text.to_string()
+        }
+    }
+    pub fn render(&self, diagnostic: &Diagnostic) -> String {
+        let severity = match diagnostic.severity {
+            Severity::Error => "error",
+        };
+        let mut out = format!(
+            "{}{}",
+            self.paint(RED, &format!("{}[{}]", severity, diagnostic.code)),
+            self.paint(BOLD, &format!(": {}", diagnostic.message))
+        );
+
+        // (line, first column, underline length, label, primary), only spans of the primary file
+        let primary_file = self.source_map.file(diagnostic.span.file_id);
+        let mut markers = vec![];
+        if let Some(file) = primary_file {
+            let spans = std::iter::once((diagnostic.span, "", true)).chain(
+                diagnostic
+                    .labels
+                    .iter()
+                    .map(|label| (label.span, label.message.as_str(), false)),
+            );
+            for (span, message, primary) in spans {
+                if span.file_id != diagnostic.span.file_id {
+                    continue;
+                }
+                let (line, column) = file.location(span.start);
+                let (end_line, end_column) = file.location(span.end);
+                // a span running over several lines is underlined up to the end of its first line
+                let length = if end_line == line {
+                    end_column.saturating_sub(column)
+                } else {
+                    file.line(line - 1).chars().count() + 1 - column
+                };
+                markers.push((line, column, length.max(1), message, primary));
+            }
+        }
+        // the primary span goes first so it wins when two markers share a line
+        markers.sort_by_key(|marker| marker.0);
+
+        let gutter_width = markers
+            .iter()
+            .map(|marker| marker.0.to_string().len())
+            .max()
+            .unwrap_or(0);
+        let gutter = " ".repeat(gutter_width);
+        let bar = self.paint(BLUE, "|");
+
+        if let Some(file) = primary_file {
+            let (line, column) = file.location(diagnostic.span.start);
+            out += &format!(
+                "\n{}{} {}:{}:{}",
+                gutter,
+                self.paint(BLUE, "-->"),
+                file.name,
+                line,
+                column
+            );
+            out += &format!("\n{} {}", gutter, bar);
+        }
+        let mut last_line = 0;
+        for (line, column, length, message, primary) in markers {
+            let source_line = primary_file.unwrap().line(line - 1);
+            if line != last_line {
+                out += &format!(
+                    "\n{} {} {}",
+                    self.paint(BLUE, &format!("{:>width$}", line, width = gutter_width)),
+                    bar,
+                    source_line
+                );
+                last_line = line;
+            }
+            // keep tabs so the marker lines up with the source above it
+            let indent: String = source_line
+                .chars()
+                .take(column - 1)
+                .map(|char| if char == '\t' { '\t' } else { ' ' })
+                .collect();
+            let (marker, colour) = if primary { ('^', RED) } else { ('-', BLUE) };
+            let underline = marker.to_string().repeat(length);
+            let underline = if message.is_empty() {
+                underline
+            } else {
+                format!("{} {}", underline, message)
+            };
+            out += &format!("\n{} {} {}{}", gutter, bar, indent, self.paint(colour, &underline));
+        }
+        for note in &diagnostic.notes {
+            out += &format!("\n{} {} note: {}", gutter, self.paint(BLUE, "="), note);
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(source: &str, diagnostic: &Diagnostic, colour: bool) -> String {
+        let mut source_map = SourceMap::new();
+        source_map.add_file("test.min", source.to_string());
+        Renderer::new(&source_map, colour).render(diagnostic)
+    }
+
+    #[test]
+    fn labels() {
+        let source = "fun f() {\n    int x <- 1;\n    int x <- 2;\n}";
+        let diagnostic = Diagnostic::error("E0503", "variable 'x' is already defined".to_string(), Span::new(0, 34, 35))
+            .with_label(Span::new(0, 18, 19), "first defined here")
+            .with_label(Span::new(0, 38, 39), "the new value")
+            .with_note("shadowing needs a new scope");
+        let expected = "\
+error[E0503]: variable 'x' is already defined
+ --> test.min:3:9
+  |
+2 |     int x <- 1;
+  |         - first defined here
+3 |     int x <- 2;
+  |         ^
+  |             - the new value
+  = note: shadowing needs a new scope";
+        assert_eq!(render(source, &diagnostic, false), expected);
+    }
+
+    #[test]
+    fn spans_at_the_end() {
+        // an error at the end of the file still gets a marker, right after the last character
+        let diagnostic = Diagnostic::error("E0101", "unexpected end of file".to_string(), Span::new(0, 10, 10));
+        let expected = "\
+error[E0101]: unexpected end of file
+ --> test.min:1:11
+  |
+1 | return 1 +
+  |           ^";
+        assert_eq!(render("return 1 +", &diagnostic, false), expected);
+    }
+
+    #[test]
+    fn colour() {
+        let diagnostic = Diagnostic::error("E0101", "oops".to_string(), Span::new(0, 0, 1));
+        let coloured = render("x", &diagnostic, true);
+        assert!(coloured.starts_with("\x1b[1;31merror[E0101]\x1b[0m\x1b[1m: oops\x1b[0m"));
+        assert!(!render("x", &diagnostic, false).contains('\x1b'));
+        assert!(use_colour(true, None));
+        assert!(!use_colour(false, None));
+        assert!(!use_colour(true, Some(OsString::from("1"))));
+        assert!(!use_colour(true, Some(OsString::new())));
+    }
+}
@@ -0,0 +1,452 @@
+use crate::diagnostic::Diagnostic;
+use crate::parser::{BinaryOperation, ConstValue, Node, UnaryOperation};
+use crate::source_map::Span;
+
+// an int is 32 bits wide in a register and in its stack slot
+pub const INT_MIN: i128 = i32::MIN as i128;
+pub const INT_MAX: i128 = i32::MAX as i128;
+
+fn compare<T: PartialOrd>(op: &BinaryOperation, left: T, right: T) -> bool {
+    match op {
+        BinaryOperation::Equal => left == right,
+        BinaryOperation::NotEqual => left != right,
+        BinaryOperation::Less => left < right,
+        BinaryOperation::LessEqual => left <= right,
+        BinaryOperation::Greater => left > right,
+        BinaryOperation::GreaterEqual => left >= right,
+        _ => unreachable!("{:?} is not a comparison", op),
+    }
+}
+fn constant(node: &Node) -> Option<&ConstValue> {
+    match node {
+        Node::Const { value_type, .. } => Some(value_type),
+        _ => None,
+    }
+}
+// whether evaluating the expression can be left out, no calls and nothing that can trap
+fn is_pure(node: &Node) -> bool {
+    match node {
+        Node::Expr { value, .. } | Node::UnaryOp { value, .. } => is_pure(value),
+        Node::Const { .. } | Node::VariableReference { .. } => true,
+        Node::BinaryOp {
+            left, op, right, ..
+        } => {
+            !matches!(op, BinaryOperation::Div | BinaryOperation::Mod)
+                && is_pure(left)
+                && is_pure(right)
+        }
+        _ => false,
+    }
+}
+
+// what an operation with an identity or absorbing operand reduces to
+enum Simplified {
+    Left,
+    Right,
+    Const(ConstValue),
+}
+
+// evaluates constant expressions of the checked AST and drops operations that leave
+// their operand unchanged, before anything is lowered
+pub struct Folder {
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Folder {
+    fn error(&mut self, code: &'static str, message: &str, span: Span) {
+        self.diagnostics
+            .push(Diagnostic::error(code, message.to_string(), span));
+    }
+    // None when the value does not fit an int, which is reported
+    fn int(&mut self, value: Option<i128>, message: &str, span: Span) -> Option<ConstValue> {
+        match value {
+            Some(value) if (INT_MIN..=INT_MAX).contains(&value) => {
+                Some(ConstValue::Integer { value })
+            }
+            _ => {
+                self.diagnostics.push(
+                    Diagnostic::error("E0602", message.to_string(), span).with_note(&format!(
+                        "an int holds values from {} to {}",
+                        INT_MIN, INT_MAX
+                    )),
+                );
+                None
+            }
+        }
+    }
+    fn float(&mut self, value: f64, span: Span) -> Option<ConstValue> {
+        if value.is_finite() {
+            Some(ConstValue::FloatingPoint { value })
+        } else {
+            self.error("E0602", "this arithmetic operation overflows float", span);
+            None
+        }
+    }
+    fn division_by_zero(&mut self, op: &BinaryOperation, span: Span) -> Option<ConstValue> {
+        let message = match op {
+            BinaryOperation::Mod => "modulo by zero",
+            _ => "division by zero",
+        };
+        self.error("E0601", message, span);
+        None
+    }
+
+    // the value of left op right, None when it cannot be computed at compile time
+    fn binary_constant(
+        &mut self,
+        left: &ConstValue,
+        op: &BinaryOperation,
+        right: &ConstValue,
+        span: Span,
+    ) -> Option<ConstValue> {
+        if op.is_comparison() {
+            let value = match (left, right) {
+                (ConstValue::Integer { value: left }, ConstValue::Integer { value: right }) => {
+                    compare(op, left, right)
+                }
+                (
+                    ConstValue::FloatingPoint { value: left },
+                    ConstValue::FloatingPoint { value: right },
+                ) => compare(op, left, right),
+                (ConstValue::Boolean { value: left }, ConstValue::Boolean { value: right }) => {
+                    compare(op, left, right)
+                }
+                (ConstValue::Character { value: left }, ConstValue::Character { value: right }) => {
+                    compare(op, left, right)
+                }
+                _ => return None,
+            };
+            return Some(ConstValue::Boolean { value });
+        }
+        match (left, right) {
+            (ConstValue::Integer { value: left }, ConstValue::Integer { value: right }) => {
+                let (left, right) = (*left, *right);
+                let value = match op {
+                    BinaryOperation::Div | BinaryOperation::Mod if right == 0 => {
+                        return self.division_by_zero(op, span)
+                    }
+                    BinaryOperation::Add => left.checked_add(right),
+                    BinaryOperation::Sub => left.checked_sub(right),
+                    BinaryOperation::Mul => left.checked_mul(right),
+                    // both truncate towards zero like idiv
+                    BinaryOperation::Div => left.checked_div(right),
+                    BinaryOperation::Mod => left.checked_rem(right),
+                    _ => return None,
+                };
+                self.int(value, "this arithmetic operation overflows int", span)
+            }
+            (ConstValue::FloatingPoint { value: left }, ConstValue::FloatingPoint { value: right }) => {
+                let value = match op {
+                    // ieee gives inf or nan here, divsd does not trap either
+                    BinaryOperation::Div if *right == 0.0 => {
+                        return Some(ConstValue::FloatingPoint { value: left / right })
+                    }
+                    BinaryOperation::Add => left + right,
+                    BinaryOperation::Sub => left - right,
+                    BinaryOperation::Mul => left * right,
+                    BinaryOperation::Div => left / right,
+                    _ => return None,
+                };
+                self.float(value, span)
+            }
+            (ConstValue::Boolean { value: left }, ConstValue::Boolean { value: right }) => {
+                let value = match op {
+                    BinaryOperation::And => *left && *right,
+                    BinaryOperation::Or => *left || *right,
+                    _ => return None,
+                };
+                Some(ConstValue::Boolean { value })
+            }
+            _ => None,
+        }
+    }
+    // x + 0, x * 1, true and x and the like, None when neither side is such an operand
+    fn simplify(left: &Node, op: &BinaryOperation, right: &Node) -> Option<Simplified> {
+        let (left_value, right_value) = (constant(left), constant(right));
+        let int = |value: Option<&ConstValue>, expected: i128| {
+            matches!(value, Some(ConstValue::Integer { value }) if *value == expected)
+        };
+        let float = |value: Option<&ConstValue>, expected: f64| {
+            matches!(value, Some(ConstValue::FloatingPoint { value }) if *value == expected)
+        };
+        let boolean = |value: Option<&ConstValue>, expected: bool| {
+            matches!(value, Some(ConstValue::Boolean { value }) if *value == expected)
+        };
+        match op {
+            BinaryOperation::Add if int(right_value, 0) => Some(Simplified::Left),
+            BinaryOperation::Add if int(left_value, 0) => Some(Simplified::Right),
+            BinaryOperation::Sub if int(right_value, 0) => Some(Simplified::Left),
+            BinaryOperation::Mul if int(right_value, 1) || float(right_value, 1.0) => Some(Simplified::Left),
+            BinaryOperation::Mul if int(left_value, 1) || float(left_value, 1.0) => Some(Simplified::Right),
+            BinaryOperation::Mul
+                if (int(right_value, 0) && is_pure(left)) || (int(left_value, 0) && is_pure(right)) =>
+            {
+                Some(Simplified::Const(ConstValue::Integer { value: 0 }))
+            }
+            BinaryOperation::Div if int(right_value, 1) || float(right_value, 1.0) => Some(Simplified::Left),
+            BinaryOperation::Mod if int(right_value, 1) && is_pure(left) => {
+                Some(Simplified::Const(ConstValue::Integer { value: 0 }))
+            }
+            // the right side of and / or only runs when the left one does not decide the result
+            BinaryOperation::And if boolean(left_value, true) => Some(Simplified::Right),
+            BinaryOperation::And if boolean(right_value, true) => Some(Simplified::Left),
+            BinaryOperation::And if boolean(left_value, false) => Some(Simplified::Left),
+            BinaryOperation::And if boolean(right_value, false) && is_pure(left) => Some(Simplified::Right),
+            BinaryOperation::Or if boolean(left_value, false) => Some(Simplified::Right),
+            BinaryOperation::Or if boolean(right_value, false) => Some(Simplified::Left),
+            BinaryOperation::Or if boolean(left_value, true) => Some(Simplified::Left),
+            BinaryOperation::Or if boolean(right_value, true) && is_pure(left) => Some(Simplified::Right),
+            _ => None,
+        }
+    }
+
+    fn expression(&mut self, node: Node) -> Node {
+        match node {
+            Node::Expr { value, span } => Node::Expr {
+                value: Box::new(self.expression(*value)),
+                span,
+            },
+            Node::FunctionCall {
+                name,
+                symbol,
+                args,
+                span,
+            } => Node::FunctionCall {
+                name,
+                symbol,
+                args: args.into_iter().map(|arg| self.expression(arg)).collect(),
+                span,
+            },
+            Node::BinaryOp {
+                left,
+                op,
+                right,
+                span,
+            } => {
+                let left = self.expression(*left);
+                let right = self.expression(*right);
+                if let (Some(left_value), Some(right_value)) = (constant(&left), constant(&right)) {
+                    if let Some(value_type) = self.binary_constant(left_value, &op, right_value, span)
+                    {
+                        return Node::Const { value_type, span };
+                    }
+                }
+                // idiv traps on a zero divisor whatever the dividend is, a constant one was
+                // already reported above
+                if matches!(op, BinaryOperation::Div | BinaryOperation::Mod)
+                    && constant(&left).is_none()
+                    && matches!(constant(&right), Some(ConstValue::Integer { value: 0 }))
+                {
+                    self.division_by_zero(&op, span);
+                }
+                match Self::simplify(&left, &op, &right) {
+                    Some(Simplified::Left) => left,
+                    Some(Simplified::Right) => right,
+                    Some(Simplified::Const(value_type)) => Node::Const { value_type, span },
+                    None => Node::BinaryOp {
+                        left: Box::new(left),
+                        op,
+                        right: Box::new(right),
+                        span,
+                    },
+                }
+            }
+            Node::UnaryOp { op, value, span } => {
+                let value = self.expression(*value);
+                let negated = match (&op, constant(&value)) {
+                    (UnaryOperation::Negate, Some(ConstValue::Integer { value })) => self.int(
+                        value.checked_neg(),
+                        "this arithmetic operation overflows int",
+                        span,
+                    ),
+                    (UnaryOperation::Negate, Some(ConstValue::FloatingPoint { value })) => {
+                        Some(ConstValue::FloatingPoint { value: -value })
+                    }
+                    _ => None,
+                };
+                match negated {
+                    Some(value_type) => Node::Const { value_type, span },
+                    None => Node::UnaryOp {
+                        op,
+                        value: Box::new(value),
+                        span,
+                    },
+                }
+            }
+            node => node,
+        }
+    }
+    fn fold(&mut self, node: &mut Node) {
+        let folded = self.expression(std::mem::replace(node, Node::Blank));
+        *node = folded;
+    }
+    fn block(&mut self, body: &mut [Node]) {
+        for node in body {
+            self.statement(node);
+        }
+    }
+    fn statement(&mut self, node: &mut Node) {
+        match node {
+            Node::Assign { value, .. }
+            | Node::Reassign { value, .. }
+            | Node::Return { value, .. } => self.fold(value),
+            Node::FunctionCall { .. } => self.fold(node),
+            Node::If {
+                condition,
+                body,
+                else_body,
+                ..
+            } => {
+                self.fold(condition);
+                self.block(body);
+                self.block(else_body);
+            }
+            Node::While {
+                condition, body, ..
+            } => {
+                self.fold(condition);
+                self.block(body);
+            }
+            Node::Break { .. } | Node::Continue { .. } => {}
+            _ => unreachable!("{:?} is not a statement", node),
+        }
+    }
+    pub fn fold_program(mut self, program: &mut Node) -> Result<(), Vec<Diagnostic>> {
+        let Node::Program { body, .. } = program else {
+            unreachable!()
+        };
+        for function in body {
+            if let Node::Function { body, .. } = function {
+                self.block(body);
+            }
+        }
+
+        if self.diagnostics.is_empty() {
+            Ok(())
+        } else {
+            Err(self.diagnostics)
+        }
+    }
+    pub fn fold_constants(program: &mut Node) -> Result<(), Vec<Diagnostic>> {
+        Folder {
+            diagnostics: vec![],
+        }
+        .fold_program(program)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+
+    // folds `return <expression>;` inside a function, the names are never resolved
+    fn fold(expression: &str) -> Result<Node, Vec<&'static str>> {
+        let source = format!("fun f() {{ return {}; }}", expression);
+        let tokens = Lexer::new().lex_text(0, source).expect("lexing failed");
+        let mut program = Parser::parse_tokens(tokens).expect("parsing failed");
+        if let Err(diagnostics) = Folder::fold_constants(&mut program) {
+            return Err(diagnostics.iter().map(|diagnostic| diagnostic.code).collect());
+        }
+        let Node::Program { mut body, .. } = program else {
+            unreachable!()
+        };
+        let Node::Function { mut body, .. } = body.remove(0) else {
+            unreachable!()
+        };
+        let Node::Return { value, .. } = body.remove(0) else {
+            unreachable!()
+        };
+        Ok(*value)
+    }
+    fn shape(node: &Node) -> String {
+        match node {
+            Node::Expr { value, .. } => shape(value),
+            Node::BinaryOp {
+                left, op, right, ..
+            } => format!("({:?} {} {})", op, shape(left), shape(right)),
+            Node::Const { value_type, .. } => match value_type {
+                ConstValue::Integer { value } => value.to_string(),
+                ConstValue::FloatingPoint { value } => format!("{:?}", value),
+                ConstValue::Boolean { value } => value.to_string(),
+                ConstValue::String { value } => format!("{:?}", value),
+                _ => unreachable!("{:?}", value_type),
+            },
+            Node::VariableReference { name, .. } => name.clone(),
+            Node::FunctionCall { name, .. } => format!("{}()", name),
+            _ => unreachable!("{:?}", node),
+        }
+    }
+    fn folded(expression: &str) -> String {
+        shape(&fold(expression).expect("folding failed"))
+    }
+
+    #[test]
+    fn constants() {
+        assert_eq!(folded("1 + 2 * 3"), "7");
+        assert_eq!(folded("(10 - 4) / (1 + 1)"), "3");
+        // truncated like idiv
+        assert_eq!(folded("7 / -2"), "-3");
+        assert_eq!(folded("-7 % 2"), "-1");
+        assert_eq!(folded("-2147483647 - 1"), "-2147483648");
+        assert_eq!(folded("1.5 * 2.0 - 0.5"), "2.5");
+        assert_eq!(folded("1 < 2 and 2.5 >= 2.5"), "true");
+        assert_eq!(folded("'a' > 'b' or 1 != 1"), "false");
+        // float division follows ieee instead of failing
+        assert_eq!(folded("1.0 / 0.0"), "inf");
+        assert_eq!(folded("0.0 / 0.0"), "NaN");
+        assert_eq!(folded("x + (2 * 3)"), "(Add x 6)");
+        // string comparisons are left to str_eq at run time
+        assert_eq!(folded("\"ab\" == \"ab\""), "(Equal \"ab\" \"ab\")");
+    }
+
+    #[test]
+    fn identities() {
+        assert_eq!(folded("x + 0"), "x");
+        assert_eq!(folded("0 + x"), "x");
+        assert_eq!(folded("x - 0"), "x");
+        assert_eq!(folded("0 - x"), "(Sub 0 x)");
+        assert_eq!(folded("x * 1"), "x");
+        assert_eq!(folded("1 * x"), "x");
+        assert_eq!(folded("x / 1"), "x");
+        assert_eq!(folded("1 / x"), "(Div 1 x)");
+        assert_eq!(folded("x * 1.0"), "x");
+        assert_eq!(folded("x / 1.0"), "x");
+    }
+
+    #[test]
+    fn absorbing_operands_need_a_pure_side() {
+        assert_eq!(folded("x * 0"), "0");
+        assert_eq!(folded("0 * (x + y)"), "0");
+        assert_eq!(folded("x % 1"), "0");
+        // a call may have side effects and a division may trap, both have to run
+        assert_eq!(folded("f() * 0"), "(Mul f() 0)");
+        assert_eq!(folded("(x / y) * 0"), "(Mul (Div x y) 0)");
+        assert_eq!(folded("f() % 1"), "(Mod f() 1)");
+    }
+
+    #[test]
+    fn logical_operators_keep_evaluation_order() {
+        assert_eq!(folded("true and x"), "x");
+        assert_eq!(folded("x and true"), "x");
+        assert_eq!(folded("false and f()"), "false");
+        assert_eq!(folded("x and false"), "false");
+        assert_eq!(folded("f() and false"), "(And f() false)");
+        assert_eq!(folded("false or x"), "x");
+        assert_eq!(folded("x or false"), "x");
+        assert_eq!(folded("true or f()"), "true");
+        assert_eq!(folded("x or true"), "true");
+        assert_eq!(folded("f() or true"), "(Or f() true)");
+    }
+
+    #[test]
+    fn diagnostics() {
+        assert_eq!(fold("1 / 0"), Err(vec!["E0601"]));
+        assert_eq!(fold("x % (2 - 2)"), Err(vec!["E0601"]));
+        assert_eq!(fold("f() / 0"), Err(vec!["E0601"]));
+        assert_eq!(fold("2147483647 + 1"), Err(vec!["E0602"]));
+        assert_eq!(fold("-2147483648 * -1"), Err(vec!["E0602"]));
+        assert_eq!(fold("-(-2147483648)"), Err(vec!["E0602"]));
+    }
+}
@@ -0,0 +1,393 @@
+use crate::checker::type_name;
+use crate::parser::VariableType;
+use std::fmt;
+
+// a virtual register, assigned exactly once
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct VReg(pub usize);
+
+// a variable or parameter, lives in memory and can be stored to any number of times
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct LocalId(pub usize);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct BlockId(pub usize);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    VReg(VReg),
+    Int(i128),
+    Bool(bool),
+    Char(u8),
+    // index into IrProgram::strings
+    Str(usize),
+    // index into IrProgram::floats
+    Float(usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CmpOp {
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+}
+
+impl CmpOp {
+    // the comparison that holds exactly when this one does not
+    pub fn inverted(self) -> Self {
+        match self {
+            CmpOp::Equal => CmpOp::NotEqual,
+            CmpOp::NotEqual => CmpOp::Equal,
+            CmpOp::Less => CmpOp::GreaterEqual,
+            CmpOp::LessEqual => CmpOp::Greater,
+            CmpOp::Greater => CmpOp::LessEqual,
+            CmpOp::GreaterEqual => CmpOp::Less,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Inst {
+    Binary {
+        dest: VReg,
+        op: BinOp,
+        left: Value,
+        right: Value,
+    },
+    Neg {
+        dest: VReg,
+        value: Value,
+    },
+    // dest is a bool, whether left op right holds
+    Compare {
+        dest: VReg,
+        op: CmpOp,
+        left: Value,
+        right: Value,
+    },
+    Load {
+        dest: VReg,
+        local: LocalId,
+    },
+    Store {
+        local: LocalId,
+        value: Value,
+    },
+    // dest is None when the function returns void
+    Call {
+        dest: Option<VReg>,
+        function: String,
+        args: Vec<Value>,
+        // takes arguments past its parameters, like printf
+        variadic: bool,
+    },
+}
+
+impl Inst {
+    // the register this instruction assigns, if any
+    pub fn dest(&self) -> Option<VReg> {
+        match self {
+            Inst::Binary { dest, .. }
+            | Inst::Neg { dest, .. }
+            | Inst::Compare { dest, .. }
+            | Inst::Load { dest, .. } => Some(*dest),
+            Inst::Call { dest, .. } => *dest,
+            Inst::Store { .. } => None,
+        }
+    }
+    pub fn uses(&self) -> Vec<&Value> {
+        match self {
+            Inst::Binary { left, right, .. } | Inst::Compare { left, right, .. } => vec![left, right],
+            Inst::Neg { value, .. } | Inst::Store { value, .. } => vec![value],
+            Inst::Load { .. } => vec![],
+            Inst::Call { args, .. } => args.iter().collect(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Terminator {
+    Jump(BlockId),
+    // compares left with right and continues in then_block when the comparison holds
+    Branch {
+        op: CmpOp,
+        left: Value,
+        right: Value,
+        then_block: BlockId,
+        else_block: BlockId,
+    },
+    Return(Option<Value>),
+    // a block the lowering has not finished yet
+    Unreachable,
+}
+
+impl Terminator {
+    pub fn uses(&self) -> Vec<&Value> {
+        match self {
+            Terminator::Branch { left, right, .. } => vec![left, right],
+            Terminator::Return(Some(value)) => vec![value],
+            Terminator::Jump(_) | Terminator::Return(None) | Terminator::Unreachable => vec![],
+        }
+    }
+    // the blocks control can continue in
+    pub fn targets(&self) -> Vec<BlockId> {
+        match self {
+            Terminator::Jump(target) => vec![*target],
+            Terminator::Branch {
+                then_block,
+                else_block,
+                ..
+            } => vec![*then_block, *else_block],
+            Terminator::Return(_) | Terminator::Unreachable => vec![],
+        }
+    }
+    pub fn targets_mut(&mut self) -> Vec<&mut BlockId> {
+        match self {
+            Terminator::Jump(target) => vec![target],
+            Terminator::Branch {
+                then_block,
+                else_block,
+                ..
+            } => vec![then_block, else_block],
+            Terminator::Return(_) | Terminator::Unreachable => vec![],
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Block {
+    pub insts: Vec<Inst>,
+    pub terminator: Terminator,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Local {
+    pub name: String,
+    pub var_type: VariableType,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct IrFunction {
+    pub name: String,
+    pub return_type: VariableType,
+    // locals the arguments are stored into on entry, in argument order
+    pub params: Vec<LocalId>,
+    pub locals: Vec<Local>,
+    // type of every virtual register
+    pub vregs: Vec<VariableType>,
+    // the entry block is the first one
+    pub blocks: Vec<Block>,
+}
+
+impl IrFunction {
+    pub fn new(name: &str, return_type: VariableType) -> Self {
+        Self {
+            name: name.to_string(),
+            return_type,
+            params: vec![],
+            locals: vec![],
+            vregs: vec![],
+            blocks: vec![],
+        }
+    }
+    pub fn value_type(&self, value: &Value) -> VariableType {
+        match value {
+            Value::VReg(vreg) => self.vregs[vreg.0],
+            Value::Int(_) => VariableType::Integer,
+            Value::Bool(_) => VariableType::Boolean,
+            Value::Char(_) => VariableType::Char,
+            Value::Str(_) => VariableType::String,
+            Value::Float(_) => VariableType::FloatingPoint,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct IrProgram {
+    pub strings: Vec<String>,
+    pub floats: Vec<f64>,
+    pub functions: Vec<IrFunction>,
+}
+
+impl fmt::Display for VReg {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "%{}", self.0)
+    }
+}
+impl fmt::Display for LocalId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "${}", self.0)
+    }
+}
+impl fmt::Display for BlockId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "bb{}", self.0)
+    }
+}
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::VReg(vreg) => write!(f, "{}", vreg),
+            Value::Int(value) => write!(f, "{}", value),
+            Value::Bool(value) => write!(f, "{}", value),
+            Value::Char(value) => write!(f, "{:?}", *value as char),
+            Value::Str(index) => write!(f, "@s{}", index),
+            Value::Float(index) => write!(f, "@f{}", index),
+        }
+    }
+}
+impl fmt::Display for BinOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            BinOp::Add => "add",
+            BinOp::Sub => "sub",
+            BinOp::Mul => "mul",
+            BinOp::Div => "div",
+            BinOp::Mod => "mod",
+        };
+        write!(f, "{}", name)
+    }
+}
+impl fmt::Display for CmpOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            CmpOp::Equal => "eq",
+            CmpOp::NotEqual => "ne",
+            CmpOp::Less => "lt",
+            CmpOp::LessEqual => "le",
+            CmpOp::Greater => "gt",
+            CmpOp::GreaterEqual => "ge",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl IrFunction {
+    fn fmt_inst(&self, inst: &Inst, f: &mut fmt::Formatter) -> fmt::Result {
+        let dest = |f: &mut fmt::Formatter, dest: &VReg| {
+            write!(f, "{}: {} = ", dest, type_name(self.vregs[dest.0]))
+        };
+        write!(f, "    ")?;
+        match inst {
+            Inst::Binary {
+                dest: to,
+                op,
+                left,
+                right,
+            } => {
+                dest(f, to)?;
+                writeln!(f, "{} {}, {}", op, left, right)
+            }
+            Inst::Neg { dest: to, value } => {
+                dest(f, to)?;
+                writeln!(f, "neg {}", value)
+            }
+            Inst::Compare {
+                dest: to,
+                op,
+                left,
+                right,
+            } => {
+                dest(f, to)?;
+                writeln!(f, "cmp {} {}, {}", op, left, right)
+            }
+            Inst::Load { dest: to, local } => {
+                dest(f, to)?;
+                writeln!(f, "load {}", local)
+            }
+            Inst::Store { local, value } => writeln!(f, "store {}, {}", local, value),
+            Inst::Call {
+                dest: to,
+                function,
+                args,
+                ..
+            } => {
+                if let Some(to) = to {
+                    dest(f, to)?;
+                }
+                let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+                writeln!(f, "call {}({})", function, args.join(", "))
+            }
+        }
+    }
+}
+
+impl fmt::Display for Terminator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Terminator::Jump(block) => write!(f, "jump {}", block),
+            Terminator::Branch {
+                op,
+                left,
+                right,
+                then_block,
+                else_block,
+            } => write!(
+                f,
+                "branch {} {}, {} ? {} : {}",
+                op, left, right, then_block, else_block
+            ),
+            Terminator::Return(Some(value)) => write!(f, "ret {}", value),
+            Terminator::Return(None) => write!(f, "ret"),
+            Terminator::Unreachable => write!(f, "unreachable"),
+        }
+    }
+}
+
+impl fmt::Display for IrFunction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let params: Vec<String> = self.params.iter().map(|param| param.to_string()).collect();
+        writeln!(
+            f,
+            "fun {}({}) -> {} {{",
+            self.name,
+            params.join(", "),
+            type_name(self.return_type)
+        )?;
+        for (id, local) in self.locals.iter().enumerate() {
+            writeln!(
+                f,
+                "    {}: {} {}",
+                LocalId(id),
+                type_name(local.var_type),
+                local.name
+            )?;
+        }
+        for (id, block) in self.blocks.iter().enumerate() {
+            writeln!(f, "  {}:", BlockId(id))?;
+            for inst in &block.insts {
+                self.fmt_inst(inst, f)?;
+            }
+            writeln!(f, "    {}", block.terminator)?;
+        }
+        writeln!(f, "}}")
+    }
+}
+
+impl fmt::Display for IrProgram {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (index, string) in self.strings.iter().enumerate() {
+            writeln!(f, "@s{} = {:?}", index, string)?;
+        }
+        for (index, float) in self.floats.iter().enumerate() {
+            writeln!(f, "@f{} = {:?}", index, float)?;
+        }
+        for function in &self.functions {
+            writeln!(f)?;
+            write!(f, "{}", function)?;
+        }
+        Ok(())
+    }
+}
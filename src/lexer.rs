@@ -1,5 +1,5 @@
-use std::collections::HashMap;
-use std::ops::Deref;
+use crate::diagnostic::Diagnostic;
+use crate::source_map::Span;
 
 #[derive(PartialEq, Eq, Debug, Clone)]
 pub enum TokenType {
@@ -14,6 +14,7 @@ pub enum TokenType {
 
     // Operation
     MathOperation,
+    #[allow(dead_code)]
     VariableMathOperation,
     AssignmentArrow,
     DirectMemberSelection,
@@ -27,6 +28,9 @@ pub enum TokenType {
     If,
     Else,
     Or,
+    While,
+    Break,
+    Continue,
 
     // symbols
     EndLine,
@@ -47,8 +51,7 @@ pub enum TokenType {
 pub struct Token {
     pub token_type: TokenType,
     pub value: String,
-    pub x: u32,
-    pub y: u32,
+    pub span: Span,
 }
 
 impl Token {
@@ -56,8 +59,7 @@ impl Token {
         Self {
             token_type,
             value,
-            x: 0,
-            y: 0,
+            span: Span::DUMMY,
         }
     }
     pub fn is_string(&self) -> bool {
@@ -72,15 +74,45 @@ impl Token {
     pub fn is_bool(&self) -> bool {
         self.token_type == TokenType::Boolean
     }
+    pub fn is_character(&self) -> bool {
+        self.token_type == TokenType::Character
+    }
     pub fn is_data_type(&self) -> bool {
-        self.is_float() || self.is_bool() || self.is_string() || self.is_integer()
+        self.is_float() || self.is_bool() || self.is_string() || self.is_integer() || self.is_character()
     }
-    pub fn true_value(&self) -> String {
-        if self.is_string() {
-            format!("\"{}\"", self.value)
-        } else {
-            self.value.clone()
-        }
+    // how the token reads in a message, `';'`, `'while'`, `"text"`
+    pub fn describe(&self) -> String {
+        let text = match self.token_type {
+            TokenType::EndOfFile => return "end of file".to_string(),
+            TokenType::String => return format!("{:?}", self.value),
+            TokenType::Character => {
+                return format!("{:?}", self.value.chars().next().unwrap_or_default())
+            }
+            _ if !self.value.is_empty() => &self.value,
+            TokenType::Import => "import",
+            TokenType::Return => "return",
+            TokenType::Fun => "fun",
+            TokenType::And => "and",
+            TokenType::If => "if",
+            TokenType::Else => "else",
+            TokenType::Or => "or",
+            TokenType::While => "while",
+            TokenType::Break => "break",
+            TokenType::Continue => "continue",
+            TokenType::AssignmentArrow => "<-",
+            TokenType::ReturnTypeArrow => "->",
+            TokenType::DirectMemberSelection => ".",
+            TokenType::EndLine => ";",
+            TokenType::BracketOpen => "[",
+            TokenType::BracketClose => "]",
+            TokenType::CurlyBracketOpen => "{",
+            TokenType::CurlyBracketClose => "}",
+            TokenType::ParenthesisOpen => "(",
+            TokenType::ParenthesisClose => ")",
+            TokenType::SeparatorComma => ",",
+            _ => "",
+        };
+        format!("'{}'", text)
     }
 }
 
@@ -89,13 +121,12 @@ pub struct Lexer {
     index: i32,
     run: bool,
     current_char: char,
-    x: i32,
-    y: i32,
-    tok_start_x: i32,
-    tok_start_y: i32,
-    log: Vec<String>,
-    error: Vec<String>,
+    file_id: usize,
+    // byte offset of every character, plus one past the end
+    offsets: Vec<usize>,
+    tok_start: usize,
     current_tokens: Vec<Token>,
+    diagnostics: Vec<Diagnostic>,
 }
 
 impl Lexer {
@@ -105,18 +136,30 @@ impl Lexer {
             index: -1,
             run: true,
             current_char: ' ',
-            x: 0,
-            y: 1,
-            tok_start_x: 0,
-            tok_start_y: 0,
-            log: vec![],
-            error: vec![],
+            file_id: 0,
+            offsets: vec![0],
+            tok_start: 0,
             current_tokens: vec![],
+            diagnostics: vec![],
         }
     }
+    fn error(&mut self, code: &'static str, message: String, span: Span) {
+        self.diagnostics.push(Diagnostic::error(code, message, span));
+    }
+    // byte offset of the character at index
+    fn offset(&self, index: i32) -> usize {
+        let index = (index.max(0) as usize).min(self.offsets.len() - 1);
+        self.offsets[index]
+    }
+    // from the start of the token up to and including the current character
+    fn token_span(&self) -> Span {
+        Span::new(self.file_id, self.tok_start, self.offset(self.index + 1))
+    }
+    fn current_span(&self) -> Span {
+        Span::new(self.file_id, self.offset(self.index), self.offset(self.index + 1))
+    }
     pub fn pos_starter(&mut self) {
-        self.tok_start_x = self.x;
-        self.tok_start_y = self.y;
+        self.tok_start = self.offset(self.index);
     }
     pub fn next_char(&mut self) -> bool {
         self.index += 1;
@@ -124,37 +167,65 @@ impl Lexer {
             false
         } else {
             self.current_char = self.text_to_lex[self.index as usize];
-            if self.current_char == '\n' {
-                self.y += 1;
-                self.x = 0;
-            } else {
-                self.x += 1;
-            }
             true
         }
     }
     pub fn get_next_char_ignore_space(&self) -> Option<char> {
         let mut ind = self.index as usize;
         ind += 1;
-        let mut character = self.text_to_lex[ind];
+        let mut character = *self.text_to_lex.get(ind)?;
         while character == ' ' {
             ind += 1;
-            character = self.text_to_lex[ind];
+            character = *self.text_to_lex.get(ind)?;
         }
         Some(character)
     }
     pub fn get_char(&self, ahead: i32) -> Option<char> {
-        Some(self.text_to_lex[(self.index + ahead) as usize])
+        self.text_to_lex.get((self.index + ahead) as usize).copied()
     }
     pub fn get_next_char(&self) -> Option<char> {
         self.get_char(1)
     }
     pub fn add_base(&mut self, tok_type: TokenType, value: String) {
         let mut tok = Token::new(tok_type, value);
-        tok.x = self.tok_start_x as u32;
-        tok.y = self.tok_start_y as u32;
+        tok.span = self.token_span();
         self.current_tokens.push(tok);
     }
+    // numbers and words are only flushed once the character after them is read
+    fn end_word(&mut self, length: usize) {
+        let end = self.tok_start + length;
+        if let Some(tok) = self.current_tokens.last_mut() {
+            tok.span.end = end;
+        }
+    }
+    fn add_number(&mut self, number: String, float: bool) {
+        let length = number.len();
+        if float {
+            self.add_float(number);
+        } else {
+            self.add_integer(number);
+        }
+        self.end_word(length);
+    }
+    fn add_word(&mut self, word: String) {
+        let length = word.len();
+        match &*word {
+            "and" => self.add_special(TokenType::And),
+            "or" => self.add_special(TokenType::Or),
+            "import" => self.add_special(TokenType::Import),
+            "return" => self.add_special(TokenType::Return),
+            "if" => self.add_special(TokenType::If),
+            "else" => self.add_special(TokenType::Else),
+            "while" => self.add_special(TokenType::While),
+            "break" => self.add_special(TokenType::Break),
+            "continue" => self.add_special(TokenType::Continue),
+            "fun" => self.add_special(TokenType::Fun),
+            "true" => self.add_special_bare(TokenType::Boolean, "true".to_string()),
+            "false" => self.add_special_bare(TokenType::Boolean, "false".to_string()),
+            _ => self.add_identifier(word),
+        }
+        self.end_word(length);
+    }
     pub fn add_special(&mut self, tok_type: TokenType) {
         self.add_base(tok_type, "".to_string());
     }
@@ -164,6 +235,71 @@ impl Lexer {
     pub fn add_string(&mut self, value: String) {
         self.add_base(TokenType::String, value);
     }
+    // 'a' or '\n', the opening quote is the current character
+    fn add_character(&mut self) {
+        let value = match self.get_next_char() {
+            Some('\\') => {
+                self.next_char();
+                self.next_char();
+                match self.current_char {
+                    'n' => '\n',
+                    't' => '\t',
+                    '0' => '\0',
+                    '\\' | '\'' => self.current_char,
+                    _ => {
+                        self.error(
+                            "E0003",
+                            format!("Unknown escape sequence '\\{}'", self.current_char),
+                            self.current_span(),
+                        );
+                        self.current_char
+                    }
+                }
+            }
+            Some('\'') => {
+                self.next_char();
+                self.error("E0006", "Empty character literal".to_string(), self.token_span());
+                return;
+            }
+            Some(character) if character != '\n' => {
+                self.next_char();
+                character
+            }
+            _ => {
+                self.error("E0006", "Unclosed character literal".to_string(), self.token_span());
+                return;
+            }
+        };
+        if self.get_next_char() != Some('\'') {
+            // skips to the closing quote on the same line, so the rest is not lexed as code
+            while let Some(character) = self.get_next_char() {
+                if character == '\n' {
+                    break;
+                }
+                self.next_char();
+                if character == '\'' {
+                    break;
+                }
+            }
+            self.error(
+                "E0006",
+                "Expected a single character between quotes".to_string(),
+                self.token_span(),
+            );
+            return;
+        }
+        self.next_char();
+        // a char is one byte
+        if value.is_ascii() {
+            self.add_base(TokenType::Character, value.to_string());
+        } else {
+            self.error(
+                "E0006",
+                format!("'{}' does not fit in a char, only ascii does", value),
+                self.token_span(),
+            );
+        }
+    }
     pub fn add_integer(&mut self, value: String) {
         self.add_base(TokenType::Integer, value);
     }
@@ -173,7 +309,7 @@ impl Lexer {
     pub fn add_identifier(&mut self, value: String) {
         self.add_base(TokenType::Identifier, value);
     }
-    fn lex(&mut self) -> Vec<Token> {
+    fn lex(&mut self) -> Result<Vec<Token>, Vec<Diagnostic>> {
         /*
         hierarchy
 
@@ -198,7 +334,6 @@ impl Lexer {
         let mut id_on = false;
         let mut num_on = false;
 
-        let mut int = false;
         let mut float = false;
 
         while self.run {
@@ -218,26 +353,31 @@ impl Lexer {
                     str_on = true;
 
                     if unknown_length_being_used {
-                        panic!(
-                            "shit something went wrong! at line {} char {}",
-                            self.y, self.tok_start_y
+                        self.error(
+                            "E0001",
+                            format!("Unexpected '\"' right after '{}'", unknown_length),
+                            self.token_span(),
                         );
+                        id_on = false;
+                        num_on = false;
+                        float = false;
+                        unknown_length = "".to_string();
                     }
 
                     unknown_length_being_used = true;
                 }
-            } else if str_on || comment_on {
+            } else if comment_on {
+                // everything up to the newline belongs to the comment
                 if self.current_char == '\n' {
-                    if str_on {
-                        panic!(
-                            "unclosed string at line {} char {}",
-                            self.tok_start_y, self.tok_start_x
-                        );
-                    } else {
-                        comment_on = false;
-                        unknown_length_being_used = false;
-                        unknown_length = "".to_string();
-                    }
+                    comment_on = false;
+                    unknown_length_being_used = false;
+                }
+            } else if str_on {
+                if self.current_char == '\n' {
+                    self.error("E0002", "Unclosed string".to_string(), self.token_span());
+                    str_on = false;
+                    unknown_length_being_used = false;
+                    unknown_length = "".to_string();
                 } else if self.current_char == '\\' {
                     self.next_char();
                     match self.current_char {
@@ -251,7 +391,11 @@ impl Lexer {
                             unknown_length += "\t";
                         }
                         ' ' => {}
-                        _ => unimplemented!(),
+                        _ => self.error(
+                            "E0003",
+                            format!("Unknown escape sequence '\\{}'", self.current_char),
+                            self.current_span(),
+                        ),
                     }
                 } else {
                     unknown_length += &self.current_char.to_string();
@@ -263,39 +407,19 @@ impl Lexer {
                     unknown_length += &self.current_char.to_string();
                     self.pos_starter();
                     num_on = true;
-                    int = true;
-
-                    if unknown_length_being_used {
-                        panic!(
-                            "shit something went wrong! at line {} char {}",
-                            self.y, self.tok_start_y
-                        );
-                    }
-
                     unknown_length_being_used = true;
                 }
             } else if self.current_char == '.'
                 && num_on
-                && num.contains(&self.get_next_char_ignore_space().expect(&format!(
-                    "expected char! at line {} char {}",
-                    self.y, self.tok_start_y
-                )))
+                && matches!(self.get_next_char_ignore_space(), Some(next) if num.contains(&next))
             {
-                int = false;
                 float = true;
                 unknown_length += ".";
             } else if allowed_for_id.contains(&self.current_char) {
                 // to avoid errors
                 if num_on {
-                    if int {
-                        self.add_integer(unknown_length.clone());
-                        int = false;
-                    } else if float {
-                        self.add_float(unknown_length.clone());
-                        float = false;
-                    } else {
-                        panic!("what? how?! at line {} char {}", self.y, self.tok_start_y);
-                    }
+                    self.add_number(unknown_length.clone(), float);
+                    float = false;
                     num_on = false;
                     unknown_length_being_used = false;
                     unknown_length = "".to_string();
@@ -307,46 +431,17 @@ impl Lexer {
                     unknown_length += &self.current_char.to_string();
                     self.pos_starter();
                     id_on = true;
-
-                    if unknown_length_being_used {
-                        panic!(
-                            "shit something went wrong! at line {} char {}",
-                            self.y, self.tok_start_y
-                        );
-                    }
-
                     unknown_length_being_used = true;
                 }
             } else {
                 if num_on {
-                    if int {
-                        self.add_integer(unknown_length.clone());
-                        int = false;
-                    } else if float {
-                        self.add_float(unknown_length.clone());
-                        float = false;
-                    } else {
-                        panic!(
-                            "what? how?! at line {} char {}",
-                            self.tok_start_y, self.tok_start_x
-                        );
-                    }
+                    self.add_number(unknown_length.clone(), float);
+                    float = false;
                     num_on = false;
                     unknown_length_being_used = false;
                     unknown_length = "".to_string();
                 } else if id_on {
-                    match &*unknown_length {
-                        "and" => self.add_special(TokenType::And),
-                        "or" => self.add_special(TokenType::Or),
-                        "import" => self.add_special(TokenType::Import),
-                        "return" => self.add_special(TokenType::Return),
-                        "if" => self.add_special(TokenType::If),
-                        "else" => self.add_special(TokenType::Else),
-                        "fun" => self.add_special(TokenType::Fun),
-                        "true" => self.add_special_bare(TokenType::Boolean, "true".to_string()),
-                        "false" => self.add_special_bare(TokenType::Boolean, "false".to_string()),
-                        _ => self.add_identifier(unknown_length.clone()),
-                    }
+                    self.add_word(unknown_length.clone());
                     id_on = false;
                     unknown_length_being_used = false;
                     unknown_length = "".to_string();
@@ -355,66 +450,43 @@ impl Lexer {
                 self.pos_starter();
                 match self.current_char {
                     '=' => {
-                        let next = self.get_next_char();
-                        if !next.is_some() {
-                            panic!(
-                                "Expected Continuation at line {} char {}",
-                                self.tok_start_y, self.tok_start_x
-                            );
-                        }
-                        let next_char = next.unwrap();
-                        if next_char == '=' {
-                            self.add_special_bare(TokenType::ComparisonOperation, "==".to_string());
+                        if self.get_next_char() == Some('=') {
                             self.next_char();
+                            self.add_special_bare(TokenType::ComparisonOperation, "==".to_string());
                         } else {
-                            panic!(
-                                "Please use assignment arrow '<-',  at line {} char {}",
-                                self.tok_start_y, self.tok_start_x
+                            let span = self.token_span();
+                            self.diagnostics.push(
+                                Diagnostic::error("E0004", "Unexpected '='".to_string(), span)
+                                    .with_note("use the assignment arrow '<-' to assign a value"),
                             );
                         }
                     }
                     '!' => {
-                        let next = self.get_next_char();
-                        if !next.is_some() {
-                            panic!(
-                                "Expected Continuation at line {} char {}",
-                                self.tok_start_y, self.tok_start_x
-                            );
-                        }
-                        let next_char = next.unwrap();
-                        if next_char == '=' {
-                            self.add_special_bare(TokenType::ComparisonOperation, "!=".to_string());
+                        if self.get_next_char() == Some('=') {
                             self.next_char();
+                            self.add_special_bare(TokenType::ComparisonOperation, "!=".to_string());
                         } else {
-                            panic!(
-                                "Unexpected '{}',  at line {} char {}",
-                                self.current_char, self.tok_start_y, self.tok_start_x
+                            self.error(
+                                "E0005",
+                                format!("Unexpected character '{}'", self.current_char),
+                                self.token_span(),
                             );
                         }
                     }
                     '%' => self.add_special_bare(TokenType::MathOperation, "%".to_string()),
                     '+' => self.add_special_bare(TokenType::MathOperation, "+".to_string()),
-                    '-' => self.add_special_bare(TokenType::MathOperation, "-".to_string()),
-                    '/' => {
-                        let next = self.get_next_char();
-                        if !next.is_some() {
-                            panic!(
-                                "Expected Continuation at line {} char {}",
-                                self.tok_start_y, self.tok_start_x
-                            );
+                    '-' => {
+                        if self.get_next_char() == Some('>') {
+                            self.next_char();
+                            self.add_special(TokenType::ReturnTypeArrow);
+                        } else {
+                            self.add_special_bare(TokenType::MathOperation, "-".to_string())
                         }
-                        let next_char = next.unwrap();
-                        if next_char == '/' {
+                    }
+                    '/' => {
+                        if self.get_next_char() == Some('/') {
                             self.pos_starter();
                             comment_on = true;
-
-                            if unknown_length_being_used {
-                                panic!(
-                                    "shit something went wrong! at line {} char {}",
-                                    self.y, self.tok_start_y
-                                );
-                            }
-
                             unknown_length_being_used = true;
                         } else {
                             self.add_special_bare(TokenType::MathOperation, "/".to_string())
@@ -427,40 +499,26 @@ impl Lexer {
                     ')' => self.add_special(TokenType::ParenthesisClose),
                     ',' => self.add_special(TokenType::SeparatorComma),
                     '>' => {
-                        let next = self.get_next_char();
-                        if !next.is_some() {
-                            panic!(
-                                "Expected Continuation at line {} char {}",
-                                self.tok_start_y, self.tok_start_x
-                            );
-                        }
-                        let next_char = next.unwrap();
-                        if next_char == '=' {
-                            self.add_special_bare(TokenType::ComparisonOperation, ">=".to_string());
+                        if self.get_next_char() == Some('=') {
                             self.next_char();
+                            self.add_special_bare(TokenType::ComparisonOperation, ">=".to_string());
                         } else {
                             self.add_special_bare(TokenType::ComparisonOperation, ">".to_string())
                         }
                     }
                     '<' => {
                         let next = self.get_next_char();
-                        if !next.is_some() {
-                            panic!(
-                                "Expected Continuation at line {} char {}",
-                                self.tok_start_y, self.tok_start_x
-                            );
-                        }
-                        let next_char = next.unwrap();
-                        if next_char == '=' {
+                        if next == Some('=') {
+                            self.next_char();
                             self.add_special_bare(TokenType::ComparisonOperation, "<=".to_string());
+                        } else if next == Some('-') {
                             self.next_char();
-                        } else if next_char == '-' {
                             self.add_special(TokenType::AssignmentArrow);
-                            self.next_char();
                         } else {
                             self.add_special_bare(TokenType::ComparisonOperation, "<".to_string())
                         }
                     }
+                    '\'' => self.add_character(),
                     '{' => self.add_special(TokenType::CurlyBracketOpen),
                     '}' => self.add_special(TokenType::CurlyBracketClose),
                     '[' => self.add_special(TokenType::BracketOpen),
@@ -468,181 +526,233 @@ impl Lexer {
                     ' ' => {}
                     '\n' => {}
                     '\t' => {}
-                    _ => {
-                        unimplemented!(
-                            "not added -> {} <-, at line {} char {}",
-                            self.current_char,
-                            self.tok_start_y,
-                            self.tok_start_x
-                        )
-                    }
+                    '\r' => {}
+                    _ => self.error(
+                        "E0005",
+                        format!("Unexpected character '{}'", self.current_char),
+                        self.token_span(),
+                    ),
                 }
             }
         }
         if num_on {
-            if int {
-                self.add_integer(unknown_length.clone());
-            } else if float {
-                self.add_float(unknown_length.clone());
-            } else {
-                panic!("what? how?")
-            }
+            self.add_number(unknown_length.clone(), float);
         } else if id_on {
-            match &*unknown_length {
-                "and" => self.add_special(TokenType::And),
-                "or" => self.add_special(TokenType::Or),
-                "import" => self.add_special(TokenType::Import),
-                "return" => self.add_special(TokenType::Return),
-                "if" => self.add_special(TokenType::If),
-                "fun" => self.add_special(TokenType::Fun),
-                "true" => self.add_special_bare(TokenType::Boolean, "true".to_string()),
-                "false" => self.add_special_bare(TokenType::Boolean, "false".to_string()),
-                _ => self.add_identifier(unknown_length.clone()),
-            }
+            self.add_word(unknown_length.clone());
         } else if str_on {
-            panic!(
-                "unclosed string at line {} char {}",
-                self.tok_start_y, self.tok_start_x
-            );
+            self.error("E0002", "Unclosed string".to_string(), self.token_span());
+        }
+        // the end of file is an empty span after the last character
+        let mut end_of_file = Token::new(TokenType::EndOfFile, "".to_string());
+        let end = self.offset(self.text_to_lex.len() as i32);
+        end_of_file.span = Span::new(self.file_id, end, end);
+        self.current_tokens.push(end_of_file);
+        if self.diagnostics.is_empty() {
+            Ok(self.current_tokens.clone())
+        } else {
+            Err(self.diagnostics.clone())
         }
-        self.add_special(TokenType::EndOfFile);
-        self.current_tokens.clone()
     }
 
-    pub fn lex_text(&mut self, text: String) -> Vec<Token> {
+    pub fn lex_text(&mut self, file_id: usize, text: String) -> Result<Vec<Token>, Vec<Diagnostic>> {
+        self.file_id = file_id;
         self.text_to_lex = text.chars().collect();
+        self.offsets = text
+            .char_indices()
+            .map(|(offset, _)| offset)
+            .chain(std::iter::once(text.len()))
+            .collect();
         self.lex()
     }
 }
 
-fn single_test(expected: Vec<(TokenType, String)>, to_lex: String) {
-    let mut lexer_test = Lexer::new();
-    let start_lex = lexer_test.lex_text(to_lex);
-    let mut res_lex = vec![];
-    for token in start_lex {
-        res_lex.push((token.token_type, token.value))
-    }
-    let res = expected == res_lex;
-    if res {
-        println!("Test Passed: {:?}", res_lex)
-    } else {
-        println!(
-            "Test Failed, \n\texpected {:?} \n\tgot      {:?}",
-            expected, res_lex
-        )
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn single_test(expected: Vec<(TokenType, String)>, to_lex: String) {
+        let tokens = Lexer::new().lex_text(0, to_lex).expect("lexing failed");
+        let res_lex: Vec<(TokenType, String)> = tokens
+            .into_iter()
+            .map(|token| (token.token_type, token.value))
+            .collect();
+        assert_eq!(expected, res_lex);
+    }
+    fn error_codes(to_lex: &str) -> Vec<&'static str> {
+        match Lexer::new().lex_text(0, to_lex.to_string()) {
+            Ok(_) => vec![],
+            Err(diagnostics) => diagnostics.iter().map(|diagnostic| diagnostic.code).collect(),
+        }
     }
-}
 
-pub fn lexer_test() {
-    println!("Running lexer tests..");
-    // (TokenType::, "".to_string())
-
-    //   single_test( vec![
-    //      (TokenType::, "".to_string()),
-    //      (TokenType::, "".to_string()),
-    //      (TokenType::, "".to_string()),
-    //      (TokenType::, "".to_string()),
-    //      (TokenType::, "".to_string()),
-    //  ],
-    // r#""#.to_string());
-
-    single_test(
-        vec![
-            (TokenType::Identifier, "int".to_string()),
-            (TokenType::Identifier, "name".to_string()),
-            (TokenType::Integer, "123".to_string()),
-            (TokenType::EndLine, "".to_string()),
-            (TokenType::EndOfFile, "".to_string()),
-        ],
-        r"int name 123;".to_string(),
-    );
-
-    single_test(
-        vec![
-            (TokenType::Identifier, "str".to_string()),
-            (TokenType::Identifier, "var".to_string()),
-            (TokenType::String, "hello".to_string()),
-            (TokenType::EndLine, "".to_string()),
-            (TokenType::EndOfFile, "".to_string()),
-        ],
-        r#"str var "hello"; "#.to_string(),
-    );
-
-    single_test(
-        vec![
-            (TokenType::If, "".to_string()),
-            (TokenType::Identifier, "name".to_string()),
-            (TokenType::ComparisonOperation, "==".to_string()),
-            (TokenType::Integer, "123".to_string()),
-            (TokenType::CurlyBracketOpen, "".to_string()),
-            (TokenType::CurlyBracketClose, "".to_string()),
-            (TokenType::EndOfFile, "".to_string()),
-        ],
-        r"if name == 123{}".to_string(),
-    );
-
-    single_test(
-        vec![
-            (TokenType::Identifier, "int".to_string()),
-            (TokenType::Identifier, "name".to_string()),
-            (TokenType::Integer, "123".to_string()),
-            (TokenType::EndLine, "".to_string()),
-            (TokenType::If, "".to_string()),
-            (TokenType::Identifier, "name".to_string()),
-            (TokenType::ComparisonOperation, ">".to_string()),
-            (TokenType::Integer, "100".to_string()),
-            (TokenType::CurlyBracketOpen, "".to_string()),
-            (TokenType::Identifier, "out".to_string()),
-            (TokenType::ParenthesisOpen, "".to_string()),
-            (TokenType::Identifier, "name".to_string()),
-            (TokenType::ParenthesisClose, "".to_string()),
-            (TokenType::EndLine, "".to_string()),
-            (TokenType::CurlyBracketClose, "".to_string()),
-            (TokenType::Else, "".to_string()),
-            (TokenType::If, "".to_string()),
-            (TokenType::Identifier, "name".to_string()),
-            (TokenType::ComparisonOperation, "<".to_string()),
-            (TokenType::Integer, "123".to_string()),
-            (TokenType::Or, "".to_string()),
-            (TokenType::Identifier, "name".to_string()),
-            (TokenType::ComparisonOperation, "<".to_string()),
-            (TokenType::Integer, "123".to_string()),
-            (TokenType::And, "".to_string()),
-            (TokenType::Identifier, "name".to_string()),
-            (TokenType::ComparisonOperation, "<".to_string()),
-            (TokenType::Integer, "123".to_string()),
-            (TokenType::CurlyBracketOpen, "".to_string()),
-            (TokenType::Identifier, "out".to_string()),
-            (TokenType::ParenthesisOpen, "".to_string()),
-            (TokenType::String, "huh?".to_string()),
-            (TokenType::ParenthesisClose, "".to_string()),
-            (TokenType::EndLine, "".to_string()),
-            (TokenType::CurlyBracketClose, "".to_string()),
-            (TokenType::Fun, "".to_string()),
-            (TokenType::Identifier, "make".to_string()),
-            (TokenType::ParenthesisOpen, "".to_string()),
-            (TokenType::ParenthesisClose, "".to_string()),
-            (TokenType::Identifier, "int".to_string()),
-            (TokenType::CurlyBracketOpen, "".to_string()),
-            (TokenType::Return, "".to_string()),
-            (TokenType::Integer, "123".to_string()),
-            (TokenType::EndLine, "".to_string()),
-            (TokenType::CurlyBracketClose, "".to_string()),
-            (TokenType::EndOfFile, "".to_string()),
-        ],
-        r#"
-   int name 123;
-   if name > 100 {
-      out(name);
-   } else if name < 123 or name < 123 and name < 123 {
-        out("huh?");
-   }
-   fun make() int {
-        return 123;
-   }
-   "#
-        .to_string(),
-    );
-
-    println!("Lexer tests complete");
+    #[test]
+    fn declaration() {
+        single_test(
+            vec![
+                (TokenType::Identifier, "int".to_string()),
+                (TokenType::Identifier, "name".to_string()),
+                (TokenType::Integer, "123".to_string()),
+                (TokenType::EndLine, "".to_string()),
+                (TokenType::EndOfFile, "".to_string()),
+            ],
+            r"int name 123;".to_string(),
+        );
+    }
+
+    #[test]
+    fn string_literal() {
+        single_test(
+            vec![
+                (TokenType::Identifier, "str".to_string()),
+                (TokenType::Identifier, "var".to_string()),
+                (TokenType::String, "hello".to_string()),
+                (TokenType::EndLine, "".to_string()),
+                (TokenType::EndOfFile, "".to_string()),
+            ],
+            r#"str var "hello"; "#.to_string(),
+        );
+    }
+
+    #[test]
+    fn comparison() {
+        single_test(
+            vec![
+                (TokenType::If, "".to_string()),
+                (TokenType::Identifier, "name".to_string()),
+                (TokenType::ComparisonOperation, "==".to_string()),
+                (TokenType::Integer, "123".to_string()),
+                (TokenType::CurlyBracketOpen, "".to_string()),
+                (TokenType::CurlyBracketClose, "".to_string()),
+                (TokenType::EndOfFile, "".to_string()),
+            ],
+            r"if name == 123{}".to_string(),
+        );
+    }
+
+    #[test]
+    fn character_literals() {
+        single_test(
+            vec![
+                (TokenType::Identifier, "char".to_string()),
+                (TokenType::Identifier, "c".to_string()),
+                (TokenType::AssignmentArrow, "".to_string()),
+                (TokenType::Character, "a".to_string()),
+                (TokenType::EndLine, "".to_string()),
+                (TokenType::Character, "\n".to_string()),
+                (TokenType::Character, "'".to_string()),
+                (TokenType::Character, " ".to_string()),
+                (TokenType::EndOfFile, "".to_string()),
+            ],
+            r"char c <- 'a'; '\n' '\'' ' '".to_string(),
+        );
+    }
+
+    #[test]
+    fn statements_and_keywords() {
+        single_test(
+            vec![
+                (TokenType::Identifier, "int".to_string()),
+                (TokenType::Identifier, "name".to_string()),
+                (TokenType::Integer, "123".to_string()),
+                (TokenType::EndLine, "".to_string()),
+                (TokenType::If, "".to_string()),
+                (TokenType::Identifier, "name".to_string()),
+                (TokenType::ComparisonOperation, ">".to_string()),
+                (TokenType::Integer, "100".to_string()),
+                (TokenType::CurlyBracketOpen, "".to_string()),
+                (TokenType::Identifier, "out".to_string()),
+                (TokenType::ParenthesisOpen, "".to_string()),
+                (TokenType::Identifier, "name".to_string()),
+                (TokenType::ParenthesisClose, "".to_string()),
+                (TokenType::EndLine, "".to_string()),
+                (TokenType::CurlyBracketClose, "".to_string()),
+                (TokenType::Else, "".to_string()),
+                (TokenType::If, "".to_string()),
+                (TokenType::Identifier, "name".to_string()),
+                (TokenType::ComparisonOperation, "<".to_string()),
+                (TokenType::Integer, "123".to_string()),
+                (TokenType::Or, "".to_string()),
+                (TokenType::Identifier, "name".to_string()),
+                (TokenType::ComparisonOperation, "<".to_string()),
+                (TokenType::Integer, "123".to_string()),
+                (TokenType::And, "".to_string()),
+                (TokenType::Identifier, "name".to_string()),
+                (TokenType::ComparisonOperation, "<".to_string()),
+                (TokenType::Integer, "123".to_string()),
+                (TokenType::CurlyBracketOpen, "".to_string()),
+                (TokenType::Identifier, "out".to_string()),
+                (TokenType::ParenthesisOpen, "".to_string()),
+                (TokenType::String, "huh?".to_string()),
+                (TokenType::ParenthesisClose, "".to_string()),
+                (TokenType::EndLine, "".to_string()),
+                (TokenType::CurlyBracketClose, "".to_string()),
+                (TokenType::Fun, "".to_string()),
+                (TokenType::Identifier, "make".to_string()),
+                (TokenType::ParenthesisOpen, "".to_string()),
+                (TokenType::ParenthesisClose, "".to_string()),
+                (TokenType::ReturnTypeArrow, "".to_string()),
+                (TokenType::Identifier, "int".to_string()),
+                (TokenType::CurlyBracketOpen, "".to_string()),
+                (TokenType::Return, "".to_string()),
+                (TokenType::Integer, "123".to_string()),
+                (TokenType::EndLine, "".to_string()),
+                (TokenType::CurlyBracketClose, "".to_string()),
+                (TokenType::EndOfFile, "".to_string()),
+            ],
+            r#"
+       int name 123;
+       if name > 100 {
+          out(name);
+       } else if name < 123 or name < 123 and name < 123 {
+            out("huh?");
+       }
+       fun make() -> int {
+            return 123;
+       }
+       "#
+            .to_string(),
+        );
+    }
+
+    #[test]
+    fn loop_control() {
+        single_test(
+            vec![
+                (TokenType::While, "".to_string()),
+                (TokenType::Identifier, "i".to_string()),
+                (TokenType::ComparisonOperation, "<".to_string()),
+                (TokenType::Integer, "10".to_string()),
+                (TokenType::CurlyBracketOpen, "".to_string()),
+                (TokenType::Break, "".to_string()),
+                (TokenType::EndLine, "".to_string()),
+                (TokenType::Continue, "".to_string()),
+                (TokenType::EndLine, "".to_string()),
+                (TokenType::CurlyBracketClose, "".to_string()),
+                (TokenType::EndOfFile, "".to_string()),
+            ],
+            r"while i < 10 { break; continue; }".to_string(),
+        );
+    }
+
+    #[test]
+    fn comments_are_skipped() {
+        single_test(
+            vec![
+                (TokenType::Return, "".to_string()),
+                (TokenType::Integer, "1".to_string()),
+                (TokenType::EndLine, "".to_string()),
+                (TokenType::EndOfFile, "".to_string()),
+            ],
+            "// a \\q comment with \"quotes\n return 1; // \\x".to_string(),
+        );
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(error_codes(r#""\q""#), vec!["E0003"]);
+        assert_eq!(error_codes("\"open\n"), vec!["E0002"]);
+        assert_eq!(error_codes("a = b"), vec!["E0004"]);
+        assert_eq!(error_codes("''"), vec!["E0006"]);
+        assert_eq!(error_codes("'ab'"), vec!["E0006"]);
+    }
 }
@@ -0,0 +1,683 @@
+use crate::checker::type_name;
+use crate::diagnostic::Diagnostic;
+use crate::ir::{
+    BinOp, Block, BlockId, CmpOp, Inst, IrFunction, IrProgram, Local, LocalId, Terminator, VReg,
+    Value,
+};
+use crate::parser::{BinaryOperation, ConstValue, Node, UnaryOperation, VariableType};
+use crate::resolver::{SymbolId, SymbolKind, SymbolTable};
+use crate::source_map::Span;
+use std::collections::HashMap;
+
+fn compare_op(op: &BinaryOperation) -> Option<CmpOp> {
+    match op {
+        BinaryOperation::Equal => Some(CmpOp::Equal),
+        BinaryOperation::NotEqual => Some(CmpOp::NotEqual),
+        BinaryOperation::Less => Some(CmpOp::Less),
+        BinaryOperation::LessEqual => Some(CmpOp::LessEqual),
+        BinaryOperation::Greater => Some(CmpOp::Greater),
+        BinaryOperation::GreaterEqual => Some(CmpOp::GreaterEqual),
+        _ => None,
+    }
+}
+
+// turns the checked AST into IR, one function at a time
+pub struct Lowerer<'a> {
+    symbols: &'a SymbolTable,
+    program: IrProgram,
+    function: IrFunction,
+    // the block new instructions are added to, None right after a return, break or continue
+    current: Option<BlockId>,
+    // blocks in the order lowering started them, which is the order they are laid out in
+    layout: Vec<BlockId>,
+    // local of every variable and parameter symbol of the current function
+    locals: HashMap<SymbolId, LocalId>,
+    // (continue, break) blocks of the enclosing loops, innermost last
+    loop_blocks: Vec<(BlockId, BlockId)>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl<'a> Lowerer<'a> {
+    pub fn new(symbols: &'a SymbolTable) -> Self {
+        Self {
+            symbols,
+            program: IrProgram::default(),
+            function: IrFunction::new("", VariableType::Void),
+            current: None,
+            layout: vec![],
+            locals: HashMap::new(),
+            loop_blocks: vec![],
+            diagnostics: vec![],
+        }
+    }
+    fn unsupported(&mut self, feature: &str, span: Span) {
+        self.diagnostics.push(Diagnostic::error(
+            "E0301",
+            format!("{} is not supported yet", feature),
+            span,
+        ));
+    }
+
+    fn float(&mut self, value: f64) -> Value {
+        self.program.floats.push(value);
+        Value::Float(self.program.floats.len() - 1)
+    }
+    fn new_vreg(&mut self, var_type: VariableType) -> VReg {
+        self.function.vregs.push(var_type);
+        VReg(self.function.vregs.len() - 1)
+    }
+    fn new_local(&mut self, symbol: SymbolId, var_type: VariableType) -> LocalId {
+        let local = self.new_temp(self.symbols.get(symbol).name.clone(), var_type);
+        self.locals.insert(symbol, local);
+        local
+    }
+    // a stack slot no variable refers to
+    fn new_temp(&mut self, name: String, var_type: VariableType) -> LocalId {
+        self.function.locals.push(Local { name, var_type });
+        LocalId(self.function.locals.len() - 1)
+    }
+    fn new_block(&mut self) -> BlockId {
+        self.function.blocks.push(Block {
+            insts: vec![],
+            terminator: Terminator::Unreachable,
+        });
+        BlockId(self.function.blocks.len() - 1)
+    }
+    // code that can never run is dropped
+    fn emit(&mut self, inst: Inst) {
+        if let Some(current) = self.current {
+            self.function.blocks[current.0].insts.push(inst);
+        }
+    }
+    fn terminate(&mut self, terminator: Terminator) {
+        if let Some(current) = self.current.take() {
+            self.function.blocks[current.0].terminator = terminator;
+        }
+    }
+    fn switch_to(&mut self, block: BlockId) {
+        self.current = Some(block);
+        self.layout.push(block);
+    }
+    // renumbers the blocks in layout order so a block mostly falls through to the next one
+    fn finish_layout(&mut self) {
+        let mut new_ids = vec![BlockId(0); self.function.blocks.len()];
+        for (new_id, old_id) in self.layout.iter().enumerate() {
+            new_ids[old_id.0] = BlockId(new_id);
+        }
+        let mut blocks = std::mem::take(&mut self.function.blocks);
+        for old_id in std::mem::take(&mut self.layout) {
+            let mut block = std::mem::replace(
+                &mut blocks[old_id.0],
+                Block {
+                    insts: vec![],
+                    terminator: Terminator::Unreachable,
+                },
+            );
+            for target in block.terminator.targets_mut() {
+                *target = new_ids[target.0];
+            }
+            self.function.blocks.push(block);
+        }
+    }
+
+    fn expression(&mut self, node: Node) -> Value {
+        match node {
+            Node::Expr { value, .. } => self.expression(*value),
+            Node::Const { value_type, .. } => match value_type {
+                ConstValue::Integer { value } => Value::Int(value),
+                ConstValue::String { value } => {
+                    self.program.strings.push(value);
+                    Value::Str(self.program.strings.len() - 1)
+                }
+                ConstValue::Boolean { value } => Value::Bool(value),
+                ConstValue::Character { value } => Value::Char(value),
+                ConstValue::FloatingPoint { value } => self.float(value),
+            },
+            Node::VariableReference { symbol, .. } => {
+                let local = self.locals[&symbol.unwrap()];
+                let dest = self.new_vreg(self.function.locals[local.0].var_type);
+                self.emit(Inst::Load { dest, local });
+                Value::VReg(dest)
+            }
+            Node::FunctionCall {
+                name,
+                symbol,
+                args,
+                span,
+            } => match self.function_call(name, symbol.unwrap(), args) {
+                Some(dest) => Value::VReg(dest),
+                None => {
+                    self.unsupported("using the result of a void function", span);
+                    Value::Int(0)
+                }
+            },
+            Node::BinaryOp {
+                op: BinaryOperation::And,
+                ..
+            } => self.logical_value(node, "and"),
+            Node::BinaryOp {
+                op: BinaryOperation::Or,
+                ..
+            } => self.logical_value(node, "or"),
+            Node::BinaryOp {
+                left, op, right, ..
+            } if op.is_comparison() => {
+                let (op, left, right) = self.comparison(*left, &op, *right);
+                let dest = self.new_vreg(VariableType::Boolean);
+                self.emit(Inst::Compare {
+                    dest,
+                    op,
+                    left,
+                    right,
+                });
+                Value::VReg(dest)
+            }
+            Node::BinaryOp {
+                left,
+                op,
+                right,
+                span,
+            } => {
+                let op = match op {
+                    BinaryOperation::Add => BinOp::Add,
+                    BinaryOperation::Sub => BinOp::Sub,
+                    BinaryOperation::Mul => BinOp::Mul,
+                    BinaryOperation::Div => BinOp::Div,
+                    BinaryOperation::Mod => BinOp::Mod,
+                    op => unreachable!("{:?} is not arithmetic", op),
+                };
+                let left = self.expression(*left);
+                let right = self.expression(*right);
+                let value_type = self.function.value_type(&left);
+                if !matches!(value_type, VariableType::Integer | VariableType::FloatingPoint) {
+                    self.unsupported(&format!("arithmetic on {} values", type_name(value_type)), span);
+                    return Value::Int(0);
+                }
+                let dest = self.new_vreg(value_type);
+                self.emit(Inst::Binary {
+                    dest,
+                    op,
+                    left,
+                    right,
+                });
+                Value::VReg(dest)
+            }
+            Node::UnaryOp { op, value, span } => match op {
+                UnaryOperation::Negate => {
+                    let value = self.expression(*value);
+                    let value_type = self.function.value_type(&value);
+                    let dest = self.new_vreg(value_type);
+                    match value_type {
+                        VariableType::Integer => self.emit(Inst::Neg { dest, value }),
+                        // multiplying by -1.0 is exact and flips only the sign, zero and NaN included
+                        VariableType::FloatingPoint => {
+                            let minus_one = self.float(-1.0);
+                            self.emit(Inst::Binary {
+                                dest,
+                                op: BinOp::Mul,
+                                left: value,
+                                right: minus_one,
+                            });
+                        }
+                        _ => {
+                            self.unsupported(&format!("negating {} values", type_name(value_type)), span);
+                            return Value::Int(0);
+                        }
+                    }
+                    Value::VReg(dest)
+                }
+            },
+            _ => unreachable!("{:?} is not an expression", node),
+        }
+    }
+    // the register holding the result, None for void functions
+    fn function_call(&mut self, name: String, symbol: SymbolId, args: Vec<Node>) -> Option<VReg> {
+        let args = args.into_iter().map(|arg| self.expression(arg)).collect();
+        let SymbolKind::Function {
+            return_type,
+            variadic,
+            ..
+        } = self.symbols.get(symbol).kind
+        else {
+            unreachable!()
+        };
+        let dest = (return_type != VariableType::Void).then(|| self.new_vreg(return_type));
+        self.emit(Inst::Call {
+            dest,
+            function: name,
+            args,
+            variadic,
+        });
+        dest
+    }
+    // the comparison and its operands, strings are compared by str_eq and its result
+    // is then compared with true
+    fn comparison(&mut self, left: Node, op: &BinaryOperation, right: Node) -> (CmpOp, Value, Value) {
+        let op = compare_op(op).unwrap();
+        let left = self.expression(left);
+        let right = self.expression(right);
+        if self.function.value_type(&left) != VariableType::String {
+            return (op, left, right);
+        }
+        let dest = self.new_vreg(VariableType::Boolean);
+        self.emit(Inst::Call {
+            dest: Some(dest),
+            function: "str_eq".to_string(),
+            args: vec![left, right],
+            variadic: false,
+        });
+        (op, Value::VReg(dest), Value::Bool(true))
+    }
+    // continues in then_block when the condition holds and in else_block otherwise
+    fn condition(&mut self, condition: Node, then_block: BlockId, else_block: BlockId) {
+        let terminator = match condition {
+            Node::Expr { value, .. } => return self.condition(*value, then_block, else_block),
+            // the right side is only reached when the left one does not decide the result
+            Node::BinaryOp {
+                left,
+                op: BinaryOperation::And,
+                right,
+                ..
+            } => {
+                let right_block = self.new_block();
+                self.condition(*left, right_block, else_block);
+                self.switch_to(right_block);
+                return self.condition(*right, then_block, else_block);
+            }
+            Node::BinaryOp {
+                left,
+                op: BinaryOperation::Or,
+                right,
+                ..
+            } => {
+                let right_block = self.new_block();
+                self.condition(*left, then_block, right_block);
+                self.switch_to(right_block);
+                return self.condition(*right, then_block, else_block);
+            }
+            Node::BinaryOp {
+                left, op, right, ..
+            } if op.is_comparison() => {
+                let (op, left, right) = self.comparison(*left, &op, *right);
+                if self.function.value_type(&left) == VariableType::FloatingPoint {
+                    // NaN needs a second look at the flags, which only Compare does
+                    let dest = self.new_vreg(VariableType::Boolean);
+                    self.emit(Inst::Compare {
+                        dest,
+                        op,
+                        left,
+                        right,
+                    });
+                    Terminator::Branch {
+                        op: CmpOp::NotEqual,
+                        left: Value::VReg(dest),
+                        right: Value::Bool(false),
+                        then_block,
+                        else_block,
+                    }
+                } else {
+                    Terminator::Branch {
+                        op,
+                        left,
+                        right,
+                        then_block,
+                        else_block,
+                    }
+                }
+            }
+            // a folded condition always goes the same way
+            Node::Const {
+                value_type: ConstValue::Boolean { value },
+                ..
+            } => Terminator::Jump(if value { then_block } else { else_block }),
+            value => Terminator::Branch {
+                op: CmpOp::NotEqual,
+                left: self.expression(value),
+                right: Value::Bool(false),
+                then_block,
+                else_block,
+            },
+        };
+        self.terminate(terminator);
+    }
+    // and / or as a value, branches like a condition and stores which way it went
+    fn logical_value(&mut self, node: Node, name: &str) -> Value {
+        let true_block = self.new_block();
+        let false_block = self.new_block();
+        let end_block = self.new_block();
+        let local = self.new_temp(name.to_string(), VariableType::Boolean);
+        self.condition(node, true_block, false_block);
+        for (block, value) in [(true_block, true), (false_block, false)] {
+            self.switch_to(block);
+            self.emit(Inst::Store {
+                local,
+                value: Value::Bool(value),
+            });
+            self.terminate(Terminator::Jump(end_block));
+        }
+        self.switch_to(end_block);
+        let dest = self.new_vreg(VariableType::Boolean);
+        self.emit(Inst::Load { dest, local });
+        Value::VReg(dest)
+    }
+    fn block(&mut self, body: Vec<Node>) {
+        for node in body {
+            self.statement(node);
+        }
+    }
+    fn statement(&mut self, node: Node) {
+        match node {
+            Node::Assign {
+                symbol,
+                var_type,
+                value,
+                ..
+            } => {
+                let value = self.expression(*value);
+                let local = self.new_local(symbol.unwrap(), var_type);
+                self.emit(Inst::Store { local, value });
+            }
+            Node::Reassign { symbol, value, .. } => {
+                let value = self.expression(*value);
+                let local = self.locals[&symbol.unwrap()];
+                self.emit(Inst::Store { local, value });
+            }
+            Node::FunctionCall {
+                name, symbol, args, ..
+            } => {
+                self.function_call(name, symbol.unwrap(), args);
+            }
+            Node::Return { value, .. } => {
+                let value = match *value {
+                    Node::Blank => None,
+                    value => Some(self.expression(value)),
+                };
+                self.terminate(Terminator::Return(value));
+            }
+            Node::If {
+                condition,
+                body,
+                else_body,
+                ..
+            } => {
+                let then_block = self.new_block();
+                let else_block = self.new_block();
+                let end_block = if else_body.is_empty() {
+                    else_block
+                } else {
+                    self.new_block()
+                };
+                self.condition(*condition, then_block, else_block);
+                self.switch_to(then_block);
+                self.block(body);
+                self.terminate(Terminator::Jump(end_block));
+                if !else_body.is_empty() {
+                    self.switch_to(else_block);
+                    self.block(else_body);
+                    self.terminate(Terminator::Jump(end_block));
+                }
+                self.switch_to(end_block);
+            }
+            Node::While {
+                condition, body, ..
+            } => {
+                let start_block = self.new_block();
+                let body_block = self.new_block();
+                let end_block = self.new_block();
+                self.terminate(Terminator::Jump(start_block));
+                self.switch_to(start_block);
+                self.condition(*condition, body_block, end_block);
+                self.switch_to(body_block);
+                self.loop_blocks.push((start_block, end_block));
+                self.block(body);
+                self.loop_blocks.pop();
+                self.terminate(Terminator::Jump(start_block));
+                self.switch_to(end_block);
+            }
+            Node::Break { .. } => {
+                let (_, break_block) = *self.loop_blocks.last().unwrap();
+                self.terminate(Terminator::Jump(break_block));
+            }
+            Node::Continue { .. } => {
+                let (continue_block, _) = *self.loop_blocks.last().unwrap();
+                self.terminate(Terminator::Jump(continue_block));
+            }
+            _ => unreachable!("{:?} is not a statement", node),
+        }
+    }
+    fn function(&mut self, name: String, return_type: VariableType, args: Vec<Node>, body: Vec<Node>) {
+        self.function = IrFunction::new(&name, return_type);
+        self.locals = HashMap::new();
+        let entry = self.new_block();
+        self.switch_to(entry);
+        for arg in args {
+            if let Node::Assign {
+                symbol, var_type, ..
+            } = arg
+            {
+                let local = self.new_local(symbol.unwrap(), var_type);
+                self.function.params.push(local);
+            }
+        }
+        self.block(body);
+        // falling off the end returns without a value
+        self.terminate(Terminator::Return(None));
+        self.finish_layout();
+        let function = std::mem::replace(&mut self.function, IrFunction::new("", VariableType::Void));
+        self.program.functions.push(function);
+    }
+    pub fn lower(mut self, program: Node) -> Result<IrProgram, Vec<Diagnostic>> {
+        let Node::Program { body, .. } = program else {
+            unreachable!()
+        };
+        for function in body {
+            if let Node::Function {
+                name,
+                return_type,
+                args,
+                body,
+                ..
+            } = function
+            {
+                self.function(name, return_type, args, body);
+            }
+        }
+
+        if self.diagnostics.is_empty() {
+            Ok(self.program)
+        } else {
+            Err(self.diagnostics)
+        }
+    }
+    pub fn lower_program(program: Node, symbols: &SymbolTable) -> Result<IrProgram, Vec<Diagnostic>> {
+        Lowerer::new(symbols).lower(program)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::checker::Checker;
+    use crate::fold::Folder;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+    use crate::resolver::Resolver;
+
+    // the IR of a program that passes every earlier stage
+    fn lower(source: &str) -> String {
+        let tokens = Lexer::new()
+            .lex_text(0, source.to_string())
+            .expect("lexing failed");
+        let mut program = Parser::parse_tokens(tokens).expect("parsing failed");
+        let symbols = Resolver::resolve_program(&mut program).expect("resolving failed");
+        Checker::check_instructions(&program, &symbols).expect("checking failed");
+        Folder::fold_constants(&mut program).expect("folding failed");
+        Lowerer::lower_program(program, &symbols)
+            .expect("lowering failed")
+            .to_string()
+    }
+
+    #[test]
+    fn while_loop() {
+        let source = "fun f(int n) { int i <- 0; while i < n { if i == 3 { break; } i <- i + 1; continue; } }";
+        let expected = r#"
+fun f($0) -> void {
+    $0: int n
+    $1: int i
+  bb0:
+    store $1, 0
+    jump bb1
+  bb1:
+    %0: int = load $1
+    %1: int = load $0
+    branch lt %0, %1 ? bb2 : bb5
+  bb2:
+    %2: int = load $1
+    branch eq %2, 3 ? bb3 : bb4
+  bb3:
+    jump bb5
+  bb4:
+    %3: int = load $1
+    %4: int = add %3, 1
+    store $1, %4
+    jump bb1
+  bb5:
+    ret
+}"#;
+        assert_eq!(lower(source).trim(), expected.trim());
+    }
+
+    #[test]
+    fn if_else_blocks() {
+        let source = "fun f(bool b) -> int { if b { put_i(1); } else { put_i(2); } return 3; }";
+        let expected = r#"
+fun f($0) -> int {
+    $0: bool b
+  bb0:
+    %0: bool = load $0
+    branch ne %0, false ? bb1 : bb2
+  bb1:
+    call put_i(1)
+    jump bb3
+  bb2:
+    call put_i(2)
+    jump bb3
+  bb3:
+    ret 3
+}"#;
+        assert_eq!(lower(source).trim(), expected.trim());
+        // without an else the false edge goes straight to the code after the if
+        let source = "fun f(bool b) { if b { put_i(1); } put_i(2); }";
+        let expected = r#"
+fun f($0) -> void {
+    $0: bool b
+  bb0:
+    %0: bool = load $0
+    branch ne %0, false ? bb1 : bb2
+  bb1:
+    call put_i(1)
+    jump bb2
+  bb2:
+    call put_i(2)
+    ret
+}"#;
+        assert_eq!(lower(source).trim(), expected.trim());
+    }
+
+    #[test]
+    fn string_equality_calls_str_eq() {
+        let source = "fun f(string a) -> bool { return a == \"b\"; }";
+        let expected = r#"
+@s0 = "b"
+
+fun f($0) -> bool {
+    $0: string a
+  bb0:
+    %0: string = load $0
+    %1: bool = call str_eq(%0, @s0)
+    %2: bool = cmp eq %1, true
+    ret %2
+}"#;
+        assert_eq!(lower(source).trim(), expected.trim());
+    }
+
+    #[test]
+    fn float_comparisons() {
+        let source = "fun f(float x, float y) { if x != y { put_i(1); } bool b <- x <= 1.5; }";
+        let expected = r#"
+@f0 = 1.5
+
+fun f($0, $1) -> void {
+    $0: float x
+    $1: float y
+    $2: bool b
+  bb0:
+    %0: float = load $0
+    %1: float = load $1
+    %2: bool = cmp ne %0, %1
+    branch ne %2, false ? bb1 : bb2
+  bb1:
+    call put_i(1)
+    jump bb2
+  bb2:
+    %3: float = load $0
+    %4: bool = cmp le %3, @f0
+    store $2, %4
+    ret
+}"#;
+        assert_eq!(lower(source).trim(), expected.trim());
+    }
+
+    #[test]
+    fn short_circuit() {
+        // the right hand side gets its own block and is jumped over
+        let source = "fun f(int a, int b) { if a < 1 and b < 2 { put_i(1); } }";
+        let expected = r#"
+fun f($0, $1) -> void {
+    $0: int a
+    $1: int b
+  bb0:
+    %0: int = load $0
+    branch lt %0, 1 ? bb1 : bb3
+  bb1:
+    %1: int = load $1
+    branch lt %1, 2 ? bb2 : bb3
+  bb2:
+    call put_i(1)
+    jump bb3
+  bb3:
+    ret
+}"#;
+        assert_eq!(lower(source).trim(), expected.trim());
+        // as a value the call only runs when b is false
+        let source = "fun g() -> bool { return true; } fun f(bool b) { bool c <- b or g(); }";
+        let expected = r#"
+fun g() -> bool {
+  bb0:
+    ret true
+}
+
+fun f($0) -> void {
+    $0: bool b
+    $1: bool or
+    $2: bool c
+  bb0:
+    %0: bool = load $0
+    branch ne %0, false ? bb2 : bb1
+  bb1:
+    %1: bool = call g()
+    branch ne %1, false ? bb2 : bb3
+  bb2:
+    store $1, true
+    jump bb4
+  bb3:
+    store $1, false
+    jump bb4
+  bb4:
+    %2: bool = load $1
+    store $2, %2
+    ret
+}"#;
+        assert_eq!(lower(source).trim(), expected.trim());
+    }
+}
@@ -1,20 +1,75 @@
 use std::fs;
 use std::fs::File;
 
+use crate::builder_dir::backend::Dialect;
+use crate::diagnostic::Renderer;
+use crate::minimal_lang::Emit;
+use crate::source_map::SourceMap;
+
+mod checker;
 mod compiler;
+mod diagnostic;
+mod fold;
+mod ir;
 mod lexer;
+mod lower;
 mod minimal_lang;
 mod parser;
+mod prelude;
+mod regalloc;
+mod resolver;
+mod source_map;
 
 mod builder_dir {
     pub mod builder_nasm;
     pub mod return_code;
     pub mod builder_gas;
+    pub mod backend;
 }
 
 fn main() {
-    let out_file = "out.asm";
-    let compiled = minimal_lang::compile("main.min", true);
+    let mut in_file = "main.min".to_string();
+    let mut dialect = Dialect::Gas;
+    let mut emit_ir = false;
+    // prints the tokens and the tree on stdout
+    let mut debug = false;
+    for arg in std::env::args().skip(1) {
+        if let Some(name) = arg.strip_prefix("--syntax=") {
+            dialect = Dialect::from_name(name).unwrap_or_else(|| {
+                eprintln!("unknown assembler syntax '{}', expected nasm or gas", name);
+                std::process::exit(1);
+            });
+        } else if arg == "--debug" {
+            debug = true;
+        } else if let Some(emit) = arg.strip_prefix("--emit=") {
+            emit_ir = match emit {
+                "ir" => true,
+                "asm" => false,
+                _ => {
+                    eprintln!("unknown output '{}', expected ir or asm", emit);
+                    std::process::exit(1);
+                }
+            };
+        } else {
+            in_file = arg;
+        }
+    }
+    let (emit, out_file) = if emit_ir {
+        (Emit::Ir, "out.ir")
+    } else {
+        (Emit::Asm(dialect), "out.asm")
+    };
+    let mut source_map = SourceMap::new();
+    let compiled = match minimal_lang::compile(&mut source_map, &in_file, emit, debug) {
+        Ok(compiled) => compiled,
+        Err(diagnostics) => {
+            let renderer = Renderer::for_stderr(&source_map);
+            for diagnostic in &diagnostics {
+                eprintln!("{}\n", renderer.render(diagnostic));
+            }
+            std::process::exit(1);
+        }
+    };
     let _res = File::create(out_file);
     let _ = fs::write(out_file, compiled);
 }
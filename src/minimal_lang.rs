@@ -1,87 +1,73 @@
+use crate::builder_dir::backend::Dialect;
+use crate::builder_dir::{builder_gas, builder_nasm};
+use crate::checker::Checker;
 use crate::compiler::Compiler;
-use crate::lexer::{Lexer, Token};
-use crate::parser::{BinaryOperation, ConstValue, Node, Parser, VariableType};
+use crate::diagnostic::Diagnostic;
+use crate::fold::Folder;
+use crate::ir::IrProgram;
+use crate::lexer::Lexer;
+use crate::lower::Lowerer;
+use crate::parser::{Node, Parser};
+use crate::resolver::{Resolver, SymbolTable};
+use crate::source_map::{SourceMap, Span};
 
-fn pre_compile(file_path: &str, debug: bool) -> Node {
-    let file_content = std::fs::read_to_string(file_path).expect("couldnt open file");
-    let lexed = Lexer::new().lex_text(file_content);
+fn pre_compile(
+    source_map: &mut SourceMap,
+    file_path: &str,
+    debug: bool,
+) -> Result<(Node, SymbolTable), Vec<Diagnostic>> {
+    let file_content = std::fs::read_to_string(file_path).map_err(|error| {
+        vec![Diagnostic::error(
+            "E0000",
+            format!("couldnt open file '{}': {}", file_path, error),
+            Span::DUMMY,
+        )]
+    })?;
+    let file_id = source_map.add_file(file_path, file_content.clone());
+    let lexed = Lexer::new().lex_text(file_id, file_content)?;
     if debug {
         for part in &lexed {
             println!("{:?}", part)
         }
         println!("--------------------------------------------------------");
     }
-    Parser::parse_tokens(lexed)
-    // Checker::check_instructions(returned_lexed.clone());
+    let mut parsed = Parser::parse_tokens(lexed)?;
+    let symbols = Resolver::resolve_program(&mut parsed)?;
+    Checker::check_instructions(&parsed, &symbols)?;
+    Folder::fold_constants(&mut parsed)?;
+    Ok((parsed, symbols))
+}
+// what compile() produces
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Emit {
+    Ir,
+    Asm(Dialect),
 }
-pub fn compile(file_path: &str, debug: bool) -> String {
-    // let parsed = pre_compile(file_path, debug);
-    // println!(
-    //     "{:#?}\n--------------------------------------------------------\n",
-    //     parsed
-    // );
-    // let program = Compiler::compile(parsed.clone());
-    // println!("{}", program);
-    let parsed = Node::Program { body: vec![
-        Box::new(Node::Function {
-            name: "main".to_string(),
-            return_type: VariableType::Integer,
-            args: vec![],
-            body: vec![
-                Box::new(Node::Assign {
-                    name: "name".to_string(),
-                    var_type: VariableType::Integer,
-                    value: Box::new(
-                        Node::Expr {
-                            value: Box::new(
-                                Node::BinaryOp {
-                                    left: Box::new(
-                                        Node::Const {
-                                            value_type: ConstValue::Integer {
-                                                value: 1
-                                            }
-                                        }
-                                    ),
-                                    op: BinaryOperation::Add,
-                                    right: Box::new(Node::Const {
-                                            value_type: ConstValue::Integer {
-                                                value: 1
-                                            }
-                                        }
-                                    )
-                                }
-                            )
-                        }
-                    )
-                }),
-                Box::new(
-                    Node::FunctionCall {
-                        name: "put_i".to_string(),
-                        args: vec![
-                            Box::new(
-                            Node::Expr {
-                                value: Box::new(
-                                    Node::VariableReference {
-                                        name: "name".to_string()
-                                    }
-                                )
-                            }
-                        )]
-                    }
-                )
-            ]
-        })
-    ]
-    };
-
-    let mut program = Compiler::new(parsed);
-    program.builder.new_string_literal("put_i_fmt_str", "%d\n");
-    // program.builder.extern_add( "printf");
 
-    program.builder.open_function("put_i");
-    program.builder.mov("esi", "edi");
-    program.builder.mov("eax", "0");
-    program.builder.call_function("printf", vec!["put_i_fmt_str"]);
-    program.builder.close_function();
-    return program.run()
+fn lower(
+    source_map: &mut SourceMap,
+    file_path: &str,
+    debug: bool,
+) -> Result<IrProgram, Vec<Diagnostic>> {
+    let (parsed, symbols) = pre_compile(source_map, file_path, debug)?;
+    if debug {
+        println!(
+            "{:#?}\n--------------------------------------------------------\n",
+            parsed
+        );
+    }
+    Lowerer::lower_program(parsed, &symbols)
+}
+pub fn compile(
+    source_map: &mut SourceMap,
+    file_path: &str,
+    emit: Emit,
+    debug: bool,
+) -> Result<String, Vec<Diagnostic>> {
+    let program = lower(source_map, file_path, debug)?;
+    Ok(match emit {
+        Emit::Ir => program.to_string(),
+        Emit::Asm(Dialect::Nasm) => Compiler::<builder_nasm::Builder>::compile(program),
+        Emit::Asm(Dialect::Gas) => Compiler::<builder_gas::Builder>::compile(program),
+    })
 }
@@ -1,65 +1,82 @@
+use crate::diagnostic::Diagnostic;
+use crate::fold::{INT_MAX, INT_MIN};
 use crate::lexer::{Token, TokenType};
+use crate::resolver::SymbolId;
+use crate::source_map::Span;
 
-pub fn token_type_to_variable_type(token_type: TokenType) -> VariableType {
-    match token_type {
-        TokenType::String => VariableType::String,
-        TokenType::Integer => VariableType::Integer,
-        TokenType::FloatingPoint => VariableType::FloatingPoint,
-        TokenType::Boolean => VariableType::Boolean,
-        _ => {
-            unimplemented!()
-        }
-    }
-}
+const TYPES: [&str; 5] = ["int", "string", "char", "bool", "float"];
 
-pub fn string_to_variable_type(string: &str) -> VariableType {
+pub fn string_to_variable_type(string: &str) -> Option<VariableType> {
     match string {
-        "string" => VariableType::String,
-        "int" => VariableType::Integer,
-        "float" => VariableType::FloatingPoint,
-        "bool" => VariableType::Boolean,
-        _ => {
-            unimplemented!()
-        }
+        "string" => Some(VariableType::String),
+        "int" => Some(VariableType::Integer),
+        "float" => Some(VariableType::FloatingPoint),
+        "bool" => Some(VariableType::Boolean),
+        "char" => Some(VariableType::Char),
+        _ => None,
     }
 }
 
-pub fn token_as_constant_node(tok: Token) -> Node {
-    let value;
-    match tok.token_type {
-        TokenType::String => {
-            value = Node::Const {
-                value_type: ConstValue::String {
-                    value: tok.value.clone(),
-                },
+// the lexer only hands over well formed literals, but they can still be too large
+pub fn token_as_constant_node(tok: Token) -> Result<Node, Diagnostic> {
+    let value_type = match tok.token_type {
+        TokenType::String => ConstValue::String {
+            value: tok.value.clone(),
+        },
+        TokenType::Integer => match tok.value.parse::<i128>() {
+            Ok(value) => ConstValue::Integer { value },
+            Err(_) => return Err(int_out_of_range(tok.span)),
+        },
+        TokenType::Boolean => match tok.value.parse::<bool>() {
+            Ok(value) => ConstValue::Boolean { value },
+            Err(_) => {
+                return Err(Diagnostic::error(
+                    "E0103",
+                    format!("Invalid boolean literal '{}'", tok.value),
+                    tok.span,
+                ))
             }
-        }
-        TokenType::Integer => {
-            value = Node::Const {
-                value_type: ConstValue::Integer {
-                    value: tok.value.parse::<i128>().unwrap(),
-                },
+        },
+        TokenType::FloatingPoint => match tok.value.parse::<f64>() {
+            Ok(value) if value.is_finite() => ConstValue::FloatingPoint { value },
+            _ => {
+                return Err(Diagnostic::error(
+                    "E0602",
+                    "float literal is out of range for float".to_string(),
+                    tok.span,
+                ))
             }
-        }
-        TokenType::Boolean => {
-            value = Node::Const {
-                value_type: ConstValue::Boolean {
-                    value: tok.value.parse::<bool>().unwrap(),
-                },
-            }
-        }
-        TokenType::FloatingPoint => {
-            value = Node::Const {
-                value_type: ConstValue::FloatingPoint {
-                    value: tok.value.parse::<f64>().unwrap(),
-                },
-            }
-        }
+        },
+        TokenType::Character => ConstValue::Character {
+            value: tok.value.bytes().next().unwrap(),
+        },
         _ => {
             unreachable!("{:?}", tok)
         }
+    };
+    Ok(Node::Const {
+        span: tok.span,
+        value_type,
+    })
+}
+
+fn int_out_of_range(span: Span) -> Diagnostic {
+    Diagnostic::error(
+        "E0602",
+        "integer literal is out of range for int".to_string(),
+        span,
+    )
+    .with_note(&format!("an int holds values from {} to {}", INT_MIN, INT_MAX))
+}
+// int literals only get their sign from a leading minus, so the range is checked after it
+fn check_int_range(node: Node) -> Result<Node, Diagnostic> {
+    match node {
+        Node::Const {
+            value_type: ConstValue::Integer { value },
+            span,
+        } if !(INT_MIN..=INT_MAX).contains(&value) => Err(int_out_of_range(span)),
+        node => Ok(node),
     }
-    value
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -68,6 +85,8 @@ pub enum ConstValue {
     Integer { value: i128 },
     Boolean { value: bool },
     FloatingPoint { value: f64 },
+    // ascii only, the lexer rejects anything else
+    Character { value: u8 },
 }
 
 #[derive(Debug, Clone, PartialEq, Copy)]
@@ -76,6 +95,7 @@ pub enum VariableType {
     Integer,
     Boolean,
     FloatingPoint,
+    Char,
 
     Void,
 }
@@ -85,389 +105,942 @@ pub enum BinaryOperation {
     Sub,
     Mul,
     Div,
+    Mod,
+
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+
+    And,
+    Or,
+}
+
+impl BinaryOperation {
+    pub fn from_token(tok: &Token) -> Option<BinaryOperation> {
+        match tok.token_type {
+            TokenType::MathOperation => match &*tok.value {
+                "+" => Some(BinaryOperation::Add),
+                "-" => Some(BinaryOperation::Sub),
+                "*" => Some(BinaryOperation::Mul),
+                "/" => Some(BinaryOperation::Div),
+                "%" => Some(BinaryOperation::Mod),
+                _ => None,
+            },
+            TokenType::ComparisonOperation => match &*tok.value {
+                "==" => Some(BinaryOperation::Equal),
+                "!=" => Some(BinaryOperation::NotEqual),
+                "<" => Some(BinaryOperation::Less),
+                "<=" => Some(BinaryOperation::LessEqual),
+                ">" => Some(BinaryOperation::Greater),
+                ">=" => Some(BinaryOperation::GreaterEqual),
+                _ => None,
+            },
+            TokenType::And => Some(BinaryOperation::And),
+            TokenType::Or => Some(BinaryOperation::Or),
+            _ => None,
+        }
+    }
+    // the operator as it is written in the source
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            BinaryOperation::Add => "+",
+            BinaryOperation::Sub => "-",
+            BinaryOperation::Mul => "*",
+            BinaryOperation::Div => "/",
+            BinaryOperation::Mod => "%",
+            BinaryOperation::Equal => "==",
+            BinaryOperation::NotEqual => "!=",
+            BinaryOperation::Less => "<",
+            BinaryOperation::LessEqual => "<=",
+            BinaryOperation::Greater => ">",
+            BinaryOperation::GreaterEqual => ">=",
+            BinaryOperation::And => "and",
+            BinaryOperation::Or => "or",
+        }
+    }
+    pub fn is_comparison(&self) -> bool {
+        matches!(
+            self,
+            BinaryOperation::Equal
+                | BinaryOperation::NotEqual
+                | BinaryOperation::Less
+                | BinaryOperation::LessEqual
+                | BinaryOperation::Greater
+                | BinaryOperation::GreaterEqual
+        )
+    }
+    // higher binds tighter, every binary operation is left associative
+    pub fn precedence(&self) -> u8 {
+        match self {
+            BinaryOperation::Or => 1,
+            BinaryOperation::And => 2,
+            BinaryOperation::Equal
+            | BinaryOperation::NotEqual
+            | BinaryOperation::Less
+            | BinaryOperation::LessEqual
+            | BinaryOperation::Greater
+            | BinaryOperation::GreaterEqual => 3,
+            BinaryOperation::Add | BinaryOperation::Sub => 4,
+            BinaryOperation::Mul | BinaryOperation::Div | BinaryOperation::Mod => 5,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum UnaryOperation {
+    Negate,
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Node {
     Program {
-        body: Vec<Box<Node>>,
+        body: Vec<Node>,
+        span: Span,
     },
     Function {
         name: String,
+        // filled in by the resolver, like every other symbol below
+        symbol: Option<SymbolId>,
         return_type: VariableType,
-        args: Vec<Box<Node>>,
-        body: Vec<Box<Node>>,
+        args: Vec<Node>,
+        body: Vec<Node>,
+        span: Span,
     },
     FunctionCall {
         name: String,
-        args: Vec<Box<Node>>,
+        symbol: Option<SymbolId>,
+        args: Vec<Node>,
+        span: Span,
     },
     Expr {
         value: Box<Node>,
+        span: Span,
     },
     Assign {
         name: String,
+        symbol: Option<SymbolId>,
         var_type: VariableType,
         value: Box<Node>,
+        span: Span,
+    },
+    // name <- value; on a variable declared earlier
+    Reassign {
+        name: String,
+        symbol: Option<SymbolId>,
+        value: Box<Node>,
+        span: Span,
     },
     VariableReference {
         name: String,
+        symbol: Option<SymbolId>,
+        span: Span,
     },
     Const {
         value_type: ConstValue,
+        span: Span,
     },
     Return {
         value: Box<Node>,
+        span: Span,
     },
     BinaryOp {
         left: Box<Node>,
         op: BinaryOperation,
         right: Box<Node>,
+        span: Span,
+    },
+    UnaryOp {
+        op: UnaryOperation,
+        value: Box<Node>,
+        span: Span,
+    },
+    If {
+        condition: Box<Node>,
+        body: Vec<Node>,
+        else_body: Vec<Node>,
+        span: Span,
     },
+    While {
+        condition: Box<Node>,
+        body: Vec<Node>,
+        span: Span,
+    },
+    Break {
+        span: Span,
+    },
+    Continue {
+        span: Span,
+    },
+    // stands in for a missing value, so there is nothing in the source to point at
     Blank,
 }
 
+impl Node {
+    pub fn span(&self) -> Span {
+        match self {
+            Node::Program { span, .. }
+            | Node::Function { span, .. }
+            | Node::FunctionCall { span, .. }
+            | Node::Expr { span, .. }
+            | Node::Assign { span, .. }
+            | Node::Reassign { span, .. }
+            | Node::VariableReference { span, .. }
+            | Node::Const { span, .. }
+            | Node::Return { span, .. }
+            | Node::BinaryOp { span, .. }
+            | Node::UnaryOp { span, .. }
+            | Node::If { span, .. }
+            | Node::While { span, .. }
+            | Node::Break { span }
+            | Node::Continue { span } => *span,
+            Node::Blank => Span::DUMMY,
+        }
+    }
+    // wraps an expression the way statements hold them
+    fn expr(value: Node) -> Node {
+        Node::Expr {
+            span: value.span(),
+            value: Box::new(value),
+        }
+    }
+}
+
 pub struct Parser {
     tokens: Vec<Token>,
     current_token: Token,
-    program: Vec<Box<Node>>,
-    scopes: u32,
+    program: Vec<Node>,
     index: usize,
+    loop_depth: u32,
+    diagnostics: Vec<Diagnostic>,
 }
 
 impl Parser {
     pub fn new(tokens: Vec<Token>) -> Self {
         Self {
             tokens,
-            current_token: Token {
-                token_type: TokenType::NullForParser,
-                value: "".to_string(),
-                x: 0,
-                y: 0,
-            },
+            current_token: Token::new(TokenType::NullForParser, "".to_string()),
             program: vec![],
-            scopes: 0,
             index: 0,
+            loop_depth: 0,
+            diagnostics: vec![],
         }
     }
-    fn error(&mut self, error_title: &str, error_body: &str) -> ! {
-        panic!(
-            "'{} Error: {}' at line {}, char {}",
-            error_title, error_body, self.current_token.x, self.current_token.y
-        );
+    fn error(&self, error_title: &str, error_body: &str) -> Diagnostic {
+        let code = match error_title {
+            "Expectation" => "E0101",
+            "Unexpected" => "E0102",
+            "Syntax" => "E0103",
+            "Semantics" => "E0104",
+            "Undefined" => "E0105",
+            "Type" => "E0106",
+            _ => "E0100",
+        };
+        Diagnostic::error(code, error_body.to_string(), self.token_span())
+    }
+    fn token_span(&self) -> Span {
+        self.current_token.span
+    }
+    // span of the token before the current one, the last token of whatever was just parsed
+    fn last_span(&self) -> Span {
+        self.tokens[self.index.saturating_sub(1)].span
     }
     fn next_token(&mut self) -> bool {
         self.index += 1;
         if self.index == self.tokens.len() {
+            self.index -= 1;
             false
         } else {
             self.current_token = self.tokens.get(self.index).unwrap().clone() as Token;
             true
         }
     }
+    fn previous_token(&mut self) {
+        self.index -= 1;
+        self.current_token = self.tokens.get(self.index).unwrap().clone() as Token;
+    }
     fn peek_next(&self) -> TokenType {
         let index = self.index + 1;
-        if self.index == self.tokens.len() {
+        if index >= self.tokens.len() {
             TokenType::NullForParser
         } else {
             (self.tokens.get(index).unwrap().clone() as Token).token_type
         }
     }
-    fn current_token_allowed_for_expr(&self) -> bool {
-        self.current_token.token_type == TokenType::ComparisonOperation
-            || self.current_token.token_type == TokenType::MathOperation
-            || self.current_token.is_data_type()
+    // skips the rest of a broken statement, stops on its `;` or on the `}` that closes it
+    fn synchronize(&mut self) {
+        let mut depth = 0;
+        loop {
+            match self.current_token.token_type {
+                TokenType::EndLine if depth == 0 => return,
+                TokenType::CurlyBracketOpen => depth += 1,
+                TokenType::CurlyBracketClose => {
+                    if depth == 0 {
+                        // this one closes the enclosing block, leave it for parse_block
+                        self.previous_token();
+                        return;
+                    }
+                    depth -= 1;
+                    if depth == 0 {
+                        return;
+                    }
+                }
+                TokenType::EndOfFile => return,
+                _ => {}
+            }
+            self.next_token();
+        }
     }
-    fn push_top_program(&mut self, node: Node) {
-        let mut current_scope = self.program.pop().unwrap();
-        match *current_scope {
-            Node::Function {
-                name,
-                return_type,
-                args,
-                mut body,
-            } => {
-                body.push(Box::new(node));
-                self.program.push(Box::new(Node::Function {
-                    name,
-                    return_type,
-                    args,
-                    body,
-                }))
+    fn parse_variable_type(&self) -> Result<VariableType, Diagnostic> {
+        match string_to_variable_type(&self.current_token.value) {
+            Some(var_type) => Ok(var_type),
+            None => Err(self.error(
+                "Type",
+                &format!("Type '{}' is not supported yet", self.current_token.value),
+            )),
+        }
+    }
+    // parses the expression starting at the current token, stops on the first token after it
+    fn parse_expression(&mut self, min_precedence: u8) -> Result<Node, Diagnostic> {
+        let mut left = self.parse_primary()?;
+        while let Some(op) = BinaryOperation::from_token(&self.current_token) {
+            let precedence = op.precedence();
+            if precedence < min_precedence {
+                break;
             }
-            _ => unimplemented!(),
+            self.next_token();
+            let right = self.parse_expression(precedence + 1)?;
+            left = Node::BinaryOp {
+                span: left.span().to(right.span()),
+                left: Box::new(left),
+                op,
+                right: Box::new(right),
+            };
         }
+        Ok(left)
     }
-    pub fn parse(&mut self) -> Node {
-        let types = vec!["int", "string", "char", "bool", "float"];
-        self.current_token = self.tokens.get(self.index).unwrap().clone() as Token;
-
+    // parses `(arg, ...)` starting at the open parenthesis, stops on the first token after it
+    fn parse_call_arguments(&mut self) -> Result<Vec<Node>, Diagnostic> {
+        let mut params = vec![];
+        self.next_token();
+        if self.current_token.token_type == TokenType::ParenthesisClose {
+            self.next_token();
+            return Ok(params);
+        }
         loop {
+            let value = self.parse_expression(0)?;
+            params.push(Node::expr(value));
             match self.current_token.token_type {
-                TokenType::Identifier => {
-                    // type name <- value;
-                    if types.contains(&&*self.current_token.value.clone()) {
-                        if self.program.len() == 0 {
-                            self.error("Semantics", "cannot Assign outside of function");
-                        }
-                        let var_type = &*self.current_token.value.clone();
-                        if !self.next_token()
-                            || self.current_token.token_type != TokenType::Identifier
-                        {
-                            self.error("Expectation", "Expected var name")
-                        }
-                        let var_name = self.current_token.value.clone();
+                TokenType::SeparatorComma => {
+                    self.next_token();
+                }
+                TokenType::ParenthesisClose => {
+                    self.next_token();
+                    break;
+                }
+                _ => return Err(self.error("Expectation", "Expected Comma or Closing Parenthesis")),
+            }
+        }
+        Ok(params)
+    }
+    fn parse_primary(&mut self) -> Result<Node, Diagnostic> {
+        if self.current_token.is_data_type() {
+            let value = token_as_constant_node(self.current_token.clone())?;
+            self.next_token();
+            check_int_range(value)
+        } else if self.current_token.token_type == TokenType::Identifier {
+            let name = self.current_token.value.clone();
+            let start = self.token_span();
+            self.next_token();
+            if self.current_token.token_type == TokenType::ParenthesisOpen {
+                let args = self.parse_call_arguments()?;
+                Ok(Node::FunctionCall {
+                    name,
+                    symbol: None,
+                    args,
+                    span: start.to(self.last_span()),
+                })
+            } else {
+                Ok(Node::VariableReference {
+                    name,
+                    symbol: None,
+                    span: start,
+                })
+            }
+        } else if self.current_token.token_type == TokenType::ParenthesisOpen {
+            let open = self.token_span();
+            self.next_token();
+            let value = self.parse_expression(0)?;
+            if self.current_token.token_type != TokenType::ParenthesisClose {
+                return Err(self
+                    .error("Expectation", "Expected Closing Parenthesis")
+                    .with_label(open, "parenthesis opened here"));
+            }
+            self.next_token();
+            Ok(value)
+        } else if self.current_token.token_type == TokenType::MathOperation
+            && self.current_token.value == "-"
+        {
+            let start = self.token_span();
+            self.next_token();
+            // only a literal right after the minus becomes a negative literal, -(2147483648)
+            // still has to fit before it is negated
+            if !self.current_token.is_data_type() {
+                let value = self.parse_primary()?;
+                return Ok(Node::UnaryOp {
+                    op: UnaryOperation::Negate,
+                    span: start.to(value.span()),
+                    value: Box::new(value),
+                });
+            }
+            let literal = token_as_constant_node(self.current_token.clone())?;
+            self.next_token();
+            let span = start.to(literal.span());
+            match literal {
+                Node::Const {
+                    value_type: ConstValue::Integer { value },
+                    ..
+                } => check_int_range(Node::Const {
+                    value_type: ConstValue::Integer { value: -value },
+                    span,
+                }),
+                Node::Const {
+                    value_type: ConstValue::FloatingPoint { value },
+                    ..
+                } => Ok(Node::Const {
+                    value_type: ConstValue::FloatingPoint { value: -value },
+                    span,
+                }),
+                value => Ok(Node::UnaryOp {
+                    op: UnaryOperation::Negate,
+                    value: Box::new(value),
+                    span,
+                }),
+            }
+        } else {
+            Err(self.error(
+                "Unexpected",
+                &format!("{} is not a valid expression", self.current_token.describe()),
+            ))
+        }
+    }
+    fn parse_assignment(&mut self) -> Result<Node, Diagnostic> {
+        // type name <- value;
+        let start = self.token_span();
+        let var_type = self.parse_variable_type()?;
+        if !self.next_token() || self.current_token.token_type != TokenType::Identifier {
+            return Err(self.error("Expectation", "Expected var name"));
+        }
+        let var_name = self.current_token.value.clone();
 
-                        if !self.next_token()
-                            || self.current_token.token_type != TokenType::AssignmentArrow
-                        {
-                            self.error("Expectation", "Expected assignment arrow")
-                        }
+        if !self.next_token() || self.current_token.token_type != TokenType::AssignmentArrow {
+            return Err(self.error("Expectation", "Expected assignment arrow"));
+        }
 
-                        let mut values = vec![];
-                        loop {
-                            self.next_token();
-
-                            if self.current_token.token_type == TokenType::EndLine {
-                                break;
-                            } else if self.current_token.token_type == TokenType::EndOfFile {
-                                self.error("Expectation", "Expected Variable Values or End of line")
-                            } else {
-                                values.push(self.current_token.clone());
-                            }
-                        }
-                        if values.len() == 0 {
-                            self.error("Expectation", "Expected Variable Values");
-                        }
+        self.next_token();
+        if self.current_token.token_type == TokenType::EndLine {
+            return Err(self.error("Expectation", "Expected Variable Values"));
+        }
+        let value = self.parse_expression(0)?;
+        if self.current_token.token_type != TokenType::EndLine {
+            return Err(self.error("Expectation", "Expected End Line"));
+        }
+        Ok(Node::Assign {
+            name: var_name,
+            symbol: None,
+            var_type,
+            value: Box::new(Node::expr(value)),
+            span: start.to(self.token_span()),
+        })
+    }
+    fn parse_reassignment(&mut self) -> Result<Node, Diagnostic> {
+        // name <- value;
+        let start = self.token_span();
+        let name = self.current_token.value.clone();
+        // parse_statement already saw the assignment arrow after the name
+        self.next_token();
+        self.next_token();
+        if self.current_token.token_type == TokenType::EndLine {
+            return Err(self.error("Expectation", "Expected Variable Values"));
+        }
+        let value = self.parse_expression(0)?;
+        if self.current_token.token_type != TokenType::EndLine {
+            return Err(self.error("Expectation", "Expected End Line"));
+        }
+        Ok(Node::Reassign {
+            name,
+            symbol: None,
+            value: Box::new(Node::expr(value)),
+            span: start.to(self.token_span()),
+        })
+    }
+    fn parse_return(&mut self) -> Result<Node, Diagnostic> {
+        let start = self.token_span();
+        self.next_token();
+        if self.current_token.token_type == TokenType::EndLine {
+            return Ok(Node::Return {
+                value: Box::new(Node::Blank),
+                span: start.to(self.token_span()),
+            });
+        }
+        let value = self.parse_expression(0)?;
+        if self.current_token.token_type != TokenType::EndLine {
+            return Err(self.error("Expectation", "Expected End Of Line"));
+        }
+        Ok(Node::Return {
+            value: Box::new(Node::expr(value)),
+            span: start.to(self.token_span()),
+        })
+    }
+    fn parse_if(&mut self) -> Result<Node, Diagnostic> {
+        let start = self.token_span();
+        self.next_token();
+        let condition = self.parse_expression(0)?;
+        if self.current_token.token_type != TokenType::CurlyBracketOpen {
+            return Err(self.error("Expectation", "Expected start of If Body"));
+        }
+        let body = self.parse_block()?;
 
-                        let value;
-                        if values.len() == 1 && values.get(0).unwrap().is_data_type() {
-                            value = Box::new(token_as_constant_node(values.pop().unwrap()));
-                        } else {
-                            unimplemented!()
-                        }
-                        self.push_top_program(Node::Assign {
-                            name: var_name,
-                            var_type: string_to_variable_type(var_type),
-                            value: Box::new(Node::Expr { value }),
-                        })
-                    } else if self.peek_next() != TokenType::NullForParser {
-                        // todo: syntax handling
-                        let name = self.current_token.value.clone();
-                        let peek = self.peek_next();
-                        match peek {
-                            TokenType::ParenthesisOpen => {
-                                self.next_token();
-                                let mut params = vec![];
-                                let mut param = vec![];
-                                loop {
-                                    self.next_token();
-
-                                    if self.current_token.token_type == TokenType::ParenthesisClose
-                                    {
-                                        if !param.is_empty() {
-                                            if param.len() != 1 {
-                                                unimplemented!()
-                                            }
-                                            params.push(Box::new(Node::Expr {
-                                                value: Box::new(token_as_constant_node(
-                                                    param.pop().unwrap(),
-                                                )),
-                                            }));
-                                            param.clear()
-                                        }
-                                        break;
-                                    } else if self.current_token.token_type
-                                        == TokenType::SeparatorComma
-                                    {
-                                        if param.len() != 1 {
-                                            unimplemented!()
-                                        }
-                                        params.push(Box::new(Node::Expr {
-                                            value: Box::new(token_as_constant_node(
-                                                param.pop().unwrap(),
-                                            )),
-                                        }));
-                                        param.clear()
-                                    } else if !self.current_token_allowed_for_expr() {
-                                        self.error(
-                                            "Unexpected",
-                                            &*format!(
-                                                "'{}'({:?}) is a non parse token",
-                                                self.current_token.value.clone(),
-                                                self.current_token.token_type
-                                            ),
-                                        )
-                                    } else {
-                                        param.push(self.current_token.clone())
-                                    }
-                                }
-                                self.push_top_program(Node::FunctionCall { name, args: params });
-                                if !self.next_token()
-                                    || self.current_token.token_type != TokenType::EndLine
-                                {
-                                    self.error("Expectation", "Expected End Line")
-                                }
-                            }
-                            _ => {
-                                unimplemented!()
-                            }
-                        }
-                    } else {
-                        self.error(
-                            "Undefined",
-                            &*format!("Unknown '{}'", self.current_token.value),
-                        )
+        let mut else_body = vec![];
+        if self.peek_next() == TokenType::Else {
+            self.next_token();
+            self.next_token();
+            match self.current_token.token_type {
+                TokenType::If => else_body.push(self.parse_if()?),
+                TokenType::CurlyBracketOpen => else_body = self.parse_block()?,
+                _ => return Err(self.error("Expectation", "Expected If or start of Else Body")),
+            }
+        }
+        Ok(Node::If {
+            condition: Box::new(Node::expr(condition)),
+            body,
+            else_body,
+            span: start.to(self.token_span()),
+        })
+    }
+    fn parse_while(&mut self) -> Result<Node, Diagnostic> {
+        let start = self.token_span();
+        self.next_token();
+        let condition = self.parse_expression(0)?;
+        if self.current_token.token_type != TokenType::CurlyBracketOpen {
+            return Err(self.error("Expectation", "Expected start of While Body"));
+        }
+        self.loop_depth += 1;
+        let body = self.parse_block();
+        self.loop_depth -= 1;
+        Ok(Node::While {
+            condition: Box::new(Node::expr(condition)),
+            body: body?,
+            span: start.to(self.token_span()),
+        })
+    }
+    // break; and continue;
+    fn parse_loop_control(&mut self) -> Result<Node, Diagnostic> {
+        let start = self.token_span();
+        let is_break = self.current_token.token_type == TokenType::Break;
+        if self.loop_depth == 0 {
+            return Err(self.error(
+                "Semantics",
+                &format!("cannot use {} outside of a loop", self.current_token.describe()),
+            ));
+        }
+        if !self.next_token() || self.current_token.token_type != TokenType::EndLine {
+            return Err(self.error("Expectation", "Expected End Line"));
+        }
+        let span = start.to(self.token_span());
+        Ok(if is_break {
+            Node::Break { span }
+        } else {
+            Node::Continue { span }
+        })
+    }
+    // parses a single statement, stops on its last token
+    fn parse_statement(&mut self) -> Result<Node, Diagnostic> {
+        match self.current_token.token_type {
+            TokenType::Identifier => {
+                if TYPES.contains(&&*self.current_token.value) {
+                    self.parse_assignment()
+                } else if self.peek_next() == TokenType::ParenthesisOpen {
+                    let name = self.current_token.value.clone();
+                    let start = self.token_span();
+                    self.next_token();
+                    let params = self.parse_call_arguments()?;
+                    if self.current_token.token_type != TokenType::EndLine {
+                        return Err(self.error("Expectation", "Expected End Line"));
                     }
+                    Ok(Node::FunctionCall {
+                        name,
+                        symbol: None,
+                        args: params,
+                        span: start.to(self.token_span()),
+                    })
+                } else if self.peek_next() == TokenType::AssignmentArrow {
+                    self.parse_reassignment()
+                } else {
+                    Err(self.error(
+                        "Expectation",
+                        &format!(
+                            "Expected '<-' or '(' after '{}'",
+                            self.current_token.value
+                        ),
+                    ))
                 }
-                TokenType::EndOfFile => break,
-                TokenType::Fun => {
-                    if !self.next_token() || self.current_token.token_type != TokenType::Identifier
-                    {
-                        self.error("Expectation", "Expected function")
+            }
+            TokenType::Return => self.parse_return(),
+            TokenType::If => self.parse_if(),
+            TokenType::While => self.parse_while(),
+            TokenType::Break | TokenType::Continue => self.parse_loop_control(),
+            _ => Err(self.error(
+                "Unexpected",
+                &format!("{} is not a valid statement", self.current_token.describe()),
+            )),
+        }
+    }
+    // parses `{ statements }` starting at the open curly bracket, stops on the closing one
+    fn parse_block(&mut self) -> Result<Vec<Node>, Diagnostic> {
+        let open = self.token_span();
+        let mut body = vec![];
+        loop {
+            self.next_token();
+            match self.current_token.token_type {
+                TokenType::CurlyBracketClose => break,
+                TokenType::EndOfFile => {
+                    return Err(self
+                        .error("Syntax", "Unclosed Scope, Expected '}'")
+                        .with_label(open, "scope opened here"))
+                }
+                _ => match self.parse_statement() {
+                    Ok(statement) => body.push(statement),
+                    Err(diagnostic) => {
+                        self.diagnostics.push(diagnostic);
+                        self.synchronize();
                     }
-                    let function_name = self.current_token.value.clone();
+                },
+            }
+        }
+        Ok(body)
+    }
+    fn parse_function(&mut self) -> Result<Node, Diagnostic> {
+        let start = self.token_span();
+        if !self.next_token() || self.current_token.token_type != TokenType::Identifier {
+            return Err(self.error("Expectation", "Expected function"));
+        }
+        let function_name = self.current_token.value.clone();
 
-                    if !self.next_token()
-                        || self.current_token.token_type != TokenType::ParenthesisOpen
-                    {
-                        self.error("Expectation", "Expected open parenthesis for argument")
-                    }
-                    let mut last_was_type = false;
-                    let mut last_was_arg = false;
-                    let mut arg_type = VariableType::String;
-                    let mut args = vec![];
-                    loop {
-                        self.next_token();
-                        match self.current_token.token_type {
-                            TokenType::ParenthesisClose => break,
-                            TokenType::Identifier => {
-                                if types.contains(&&*self.current_token.value) {
-                                    if last_was_type {
-                                        self.error("Expectation", "Expected Variable Name")
-                                    } else if last_was_arg {
-                                        self.error("Expectation", "Expected Comma for separation")
-                                    } else {
-                                        last_was_type = true;
-                                        arg_type = string_to_variable_type(
-                                            &*self.current_token.value.clone(),
-                                        )
-                                    }
-                                } else {
-                                    if !last_was_type {
-                                        self.error("Expectation", "Expected Parameter Type")
-                                    } else {
-                                        last_was_type = false;
-                                        last_was_arg = true;
-                                        args.push(Box::new(Node::Assign {
-                                            name: self.current_token.value.clone(),
-                                            var_type: arg_type,
-                                            value: Box::new(Node::Blank),
-                                        }))
-                                    }
-                                }
-                            }
-                            TokenType::SeparatorComma => {
-                                if !last_was_arg {
-                                    self.error("Expectation", "Expected Parameter Type")
-                                }
-                                last_was_arg = false;
-                            }
-                            _ => unimplemented!(),
-                        }
-                    }
-                    if !self.next_token()
-                        || self.current_token.token_type == TokenType::ReturnTypeArrow
-                    {
-                        if !self.next_token()
-                            || self.current_token.token_type != TokenType::Identifier
-                        {
-                            self.error("Expectation", "Expected Return Type")
-                        } else if !types.contains(&&*self.current_token.value) {
-                            self.error("Type", "Invalid Return Type")
-                        }
-                        let return_type =
-                            string_to_variable_type(&*self.current_token.value.clone());
-                        if !self.next_token()
-                            || self.current_token.token_type != TokenType::Identifier
-                        {
-                            self.error("Expectation", "Expected start of Function Body")
-                        }
-                        self.program.push(Box::new(Node::Function {
-                            name: function_name,
-                            return_type,
-                            args,
-                            body: vec![],
-                        }));
-                        self.scopes += 1
-                    } else if self.current_token.token_type == TokenType::CurlyBracketOpen {
-                        self.program.push(Box::new(Node::Function {
-                            name: function_name,
-                            return_type: VariableType::String,
-                            args,
-                            body: vec![],
-                        }));
-                        self.scopes += 1;
-                    } else {
-                        self.error(
-                            "Expectation",
-                            "Expected A ReturnTypeArrow or A Curly Bracket",
-                        )
+        if !self.next_token() || self.current_token.token_type != TokenType::ParenthesisOpen {
+            return Err(self.error("Expectation", "Expected open parenthesis for argument"));
+        }
+        let mut last_was_type = false;
+        let mut last_was_arg = false;
+        let mut arg_type = VariableType::String;
+        let mut arg_type_span = Span::DUMMY;
+        let mut args = vec![];
+        loop {
+            self.next_token();
+            match self.current_token.token_type {
+                TokenType::ParenthesisClose => {
+                    if last_was_type {
+                        return Err(self.error("Expectation", "Expected Variable Name"));
+                    } else if !last_was_arg && !args.is_empty() {
+                        // a comma right before the closing parenthesis
+                        return Err(self.error("Expectation", "Expected Parameter Type"));
                     }
+                    break;
                 }
-                TokenType::CurlyBracketClose => {
-                    self.scopes -= 1;
-                }
-                TokenType::Return => {
-                    let mut values = vec![];
-                    loop {
-                        self.next_token();
-
-                        if self.current_token.token_type == TokenType::EndOfFile {
-                            self.error("Expectation", "Expected End Of Line")
-                        } else if self.current_token.token_type == TokenType::EndLine {
-                            break;
-                        } else if self.current_token_allowed_for_expr() {
-                            values.push(self.current_token.clone())
+                TokenType::Identifier => {
+                    if TYPES.contains(&&*self.current_token.value) {
+                        if last_was_type {
+                            return Err(self.error("Expectation", "Expected Variable Name"));
+                        } else if last_was_arg {
+                            return Err(self.error("Expectation", "Expected Comma for separation"));
                         } else {
-                            self.error(
-                                "Unexpected",
-                                &*format!(
-                                    "'{}'({:?}) is a non parse token",
-                                    self.current_token.value.clone(),
-                                    self.current_token.token_type
-                                ),
-                            )
+                            last_was_type = true;
+                            arg_type_span = self.token_span();
+                            arg_type = self.parse_variable_type()?
                         }
+                    } else if !last_was_type {
+                        return Err(self.error("Expectation", "Expected Parameter Type"));
+                    } else {
+                        last_was_type = false;
+                        last_was_arg = true;
+                        args.push(Node::Assign {
+                            name: self.current_token.value.clone(),
+                            symbol: None,
+                            var_type: arg_type,
+                            value: Box::new(Node::Blank),
+                            span: arg_type_span.to(self.token_span()),
+                        })
                     }
-                    if values.len() != 1 {
-                        unimplemented!()
+                }
+                TokenType::SeparatorComma => {
+                    if !last_was_arg {
+                        return Err(self.error("Expectation", "Expected Parameter Type"));
                     }
-                    let value;
-                    value = Box::new(token_as_constant_node(values.pop().unwrap()));
-                    self.push_top_program(Node::Return {
-                        value: Box::new(Node::Expr { value }),
-                    })
+                    last_was_arg = false;
                 }
                 _ => {
-                    unimplemented!("{:?}", self.current_token)
+                    return Err(self.error(
+                        "Unexpected",
+                        &format!(
+                            "{} is not allowed in a parameter list",
+                            self.current_token.describe()
+                        ),
+                    ))
                 }
             }
-
-            self.next_token();
         }
-        if self.scopes != 0 {
-            self.error(
-                "Syntax",
-                &*format!("Unclosed Scopes, {} scopes unclosed", self.scopes),
-            );
+        let return_type;
+        if !self.next_token() || self.current_token.token_type == TokenType::ReturnTypeArrow {
+            if !self.next_token() || self.current_token.token_type != TokenType::Identifier {
+                return Err(self.error("Expectation", "Expected Return Type"));
+            }
+            return_type = match &*self.current_token.value {
+                // the same as leaving the arrow out
+                "void" => VariableType::Void,
+                name if TYPES.contains(&name) => self.parse_variable_type()?,
+                name => {
+                    return Err(self.error(
+                        "Type",
+                        &format!(
+                            "'{}' is not a return type, expected void, {}",
+                            name,
+                            TYPES.join(", ")
+                        ),
+                    ))
+                }
+            };
+            if !self.next_token() || self.current_token.token_type != TokenType::CurlyBracketOpen {
+                return Err(self.error("Expectation", "Expected start of Function Body"));
+            }
+        } else if self.current_token.token_type == TokenType::CurlyBracketOpen {
+            return_type = VariableType::Void;
+        } else {
+            return Err(self.error(
+                "Expectation",
+                "Expected A ReturnTypeArrow or A Curly Bracket",
+            ));
         }
+        let body = self.parse_block()?;
+        Ok(Node::Function {
+            name: function_name,
+            symbol: None,
+            return_type,
+            args,
+            body,
+            span: start.to(self.token_span()),
+        })
+    }
+    pub fn parse(&mut self) -> Result<Node, Vec<Diagnostic>> {
+        self.current_token = self.tokens.get(self.index).unwrap().clone() as Token;
 
-        Node::Program {
-            body: self.program.clone(),
+        loop {
+            let result = match self.current_token.token_type {
+                TokenType::EndOfFile => break,
+                TokenType::Fun => self.parse_function(),
+                TokenType::Identifier if TYPES.contains(&&*self.current_token.value) => {
+                    Err(self.error("Semantics", "cannot Assign outside of function"))
+                }
+                _ => Err(self.error(
+                    "Unexpected",
+                    &format!(
+                        "{} is not allowed outside of function",
+                        self.current_token.describe()
+                    ),
+                )),
+            };
+            match result {
+                Ok(function) => {
+                    self.program.push(function);
+                    self.next_token();
+                }
+                Err(diagnostic) => {
+                    // carry on from the next function
+                    self.diagnostics.push(diagnostic);
+                    while self.next_token() && self.current_token.token_type != TokenType::Fun {}
+                }
+            }
+        }
+
+        if !self.diagnostics.is_empty() {
+            return Err(self.diagnostics.clone());
         }
+        Ok(Node::Program {
+            body: self.program.clone(),
+            span: self.tokens[0].span.to(self.current_token.span),
+        })
     }
-    pub fn parse_tokens(tokens: Vec<Token>) -> Node {
+    pub fn parse_tokens(tokens: Vec<Token>) -> Result<Node, Vec<Diagnostic>> {
         Parser::new(tokens).parse()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+
+    fn parser(source: &str) -> Parser {
+        let tokens = Lexer::new()
+            .lex_text(0, source.to_string())
+            .expect("lexing failed");
+        let mut parser = Parser::new(tokens);
+        parser.current_token = parser.tokens[0].clone();
+        parser
+    }
+    // the tree as an s-expression, (op left right)
+    fn shape(node: &Node) -> String {
+        match node {
+            Node::Expr { value, .. } => shape(value),
+            Node::BinaryOp {
+                left, op, right, ..
+            } => format!("({:?} {} {})", op, shape(left), shape(right)),
+            Node::UnaryOp { op, value, .. } => format!("({:?} {})", op, shape(value)),
+            Node::Const {
+                value_type: ConstValue::Integer { value },
+                ..
+            } => value.to_string(),
+            Node::VariableReference { name, .. } => name.clone(),
+            Node::FunctionCall { name, args, .. } => format!(
+                "{}({})",
+                name,
+                args.iter().map(shape).collect::<Vec<_>>().join(", ")
+            ),
+            _ => unreachable!("{:?}", node),
+        }
+    }
+    fn expression(source: &str) -> String {
+        let mut parser = parser(source);
+        let node = parser.parse_expression(0).expect("parsing failed");
+        assert_eq!(parser.current_token.token_type, TokenType::EndOfFile);
+        shape(&node)
+    }
+    fn messages(source: &str) -> Vec<String> {
+        let tokens = Lexer::new()
+            .lex_text(0, source.to_string())
+            .expect("lexing failed");
+        match Parser::parse_tokens(tokens) {
+            Ok(_) => vec![],
+            Err(diagnostics) => diagnostics.into_iter().map(|diagnostic| diagnostic.message).collect(),
+        }
+    }
+    fn error_codes(source: &str) -> Vec<&'static str> {
+        let tokens = Lexer::new()
+            .lex_text(0, source.to_string())
+            .expect("lexing failed");
+        match Parser::parse_tokens(tokens) {
+            Ok(_) => vec![],
+            Err(diagnostics) => diagnostics.iter().map(|diagnostic| diagnostic.code).collect(),
+        }
+    }
+
+    #[test]
+    fn precedence() {
+        assert_eq!(expression("1 + 2 * 3"), "(Add 1 (Mul 2 3))");
+        assert_eq!(expression("1 * 2 + 3"), "(Add (Mul 1 2) 3)");
+        assert_eq!(expression("a - b % c"), "(Sub a (Mod b c))");
+        assert_eq!(expression("a + 1 < b * 2"), "(Less (Add a 1) (Mul b 2))");
+        assert_eq!(
+            expression("a < b and c == d or e"),
+            "(Or (And (Less a b) (Equal c d)) e)"
+        );
+        assert_eq!(expression("a or b and c"), "(Or a (And b c))");
+    }
+
+    #[test]
+    fn associativity() {
+        assert_eq!(expression("1 - 2 - 3"), "(Sub (Sub 1 2) 3)");
+        assert_eq!(expression("8 / 4 / 2"), "(Div (Div 8 4) 2)");
+        assert_eq!(expression("a % b * c"), "(Mul (Mod a b) c)");
+        assert_eq!(expression("a == b != c"), "(NotEqual (Equal a b) c)");
+        assert_eq!(expression("a and b and c"), "(And (And a b) c)");
+        assert_eq!(expression("a or b or c"), "(Or (Or a b) c)");
+    }
+
+    #[test]
+    fn primaries() {
+        assert_eq!(expression("(1 + 2) * 3"), "(Mul (Add 1 2) 3)");
+        assert_eq!(expression("1 - (2 - 3)"), "(Sub 1 (Sub 2 3))");
+        assert_eq!(expression("-2 * 3"), "(Mul -2 3)");
+        assert_eq!(expression("-x * 3"), "(Mul (Negate x) 3)");
+        assert_eq!(expression("f(1 + 2, g(x)) * y"), "(Mul f((Add 1 2), g(x)) y)");
+        assert_eq!(expression("f()"), "f()");
+    }
+
+    #[test]
+    fn expression_errors() {
+        assert!(parser("(1 + 2").parse_expression(0).is_err());
+        assert!(parser("1 +").parse_expression(0).is_err());
+        assert!(parser("f(1 2)").parse_expression(0).is_err());
+    }
+
+    #[test]
+    fn literal_out_of_range() {
+        let source = "fun main() { int x <- 99999999999999999999999999999999999999999999; }";
+        assert_eq!(error_codes(source), vec!["E0602"]);
+        assert_eq!(expression("-2147483648"), "-2147483648");
+        assert_eq!(expression("2147483647"), "2147483647");
+        let range_error = |source: &str| parser(source).parse_expression(0).map_err(|error| error.code);
+        assert_eq!(range_error("2147483648"), Err("E0602"));
+        assert_eq!(range_error("-2147483649"), Err("E0602"));
+        assert_eq!(range_error("-(2147483648)"), Err("E0602"));
+        assert_eq!(range_error("(-2147483648)").map(|node| shape(&node)), Ok("-2147483648".to_string()));
+    }
+
+    #[test]
+    fn parameter_lists() {
+        assert_eq!(error_codes("fun f() {}"), Vec::<&str>::new());
+        assert_eq!(error_codes("fun f(int a, bool b) {}"), Vec::<&str>::new());
+        assert_eq!(error_codes("fun f(int) {}"), vec!["E0101"]);
+        assert_eq!(error_codes("fun f(int a,) {}"), vec!["E0101"]);
+        assert_eq!(error_codes("fun f(a) {}"), vec!["E0101"]);
+    }
+
+    #[test]
+    fn return_types() {
+        let return_type = |source: &str| {
+            let tokens = Lexer::new()
+                .lex_text(0, source.to_string())
+                .expect("lexing failed");
+            match Parser::parse_tokens(tokens) {
+                Ok(Node::Program { body, .. }) => match &body[0] {
+                    Node::Function { return_type, .. } => *return_type,
+                    node => unreachable!("{:?}", node),
+                },
+                result => unreachable!("{:?}", result),
+            }
+        };
+        assert_eq!(return_type("fun f() { return; }"), VariableType::Void);
+        assert_eq!(return_type("fun f() -> void { return; }"), VariableType::Void);
+        assert_eq!(return_type("fun f() -> float { return 1.0; }"), VariableType::FloatingPoint);
+        assert_eq!(error_codes("fun f() -> foo {}"), vec!["E0106"]);
+    }
+
+    #[test]
+    fn messages_quote_the_source() {
+        assert_eq!(messages("fun f() { ; }"), vec!["';' is not a valid statement"]);
+        assert_eq!(messages("fun f() { return 1 + ; }"), vec!["';' is not a valid expression"]);
+        assert_eq!(
+            messages("fun f() { return 1 +"),
+            vec!["end of file is not a valid expression", "Unclosed Scope, Expected '}'"]
+        );
+        assert_eq!(messages("fun f() { put_s(else); }"), vec!["'else' is not a valid expression"]);
+        assert_eq!(messages("fun f() { break; }"), vec!["cannot use 'break' outside of a loop"]);
+        assert_eq!(messages("fun f() { continue; }"), vec!["cannot use 'continue' outside of a loop"]);
+        assert_eq!(messages("fun f(int a; ) {}"), vec!["';' is not allowed in a parameter list"]);
+        assert_eq!(messages("return 1;"), vec!["'return' is not allowed outside of function"]);
+        assert_eq!(messages("x <- 1;"), vec!["'x' is not allowed outside of function"]);
+        assert_eq!(messages("\"text\""), vec!["\"text\" is not allowed outside of function"]);
+        assert_eq!(
+            messages("fun f() -> foo {}"),
+            vec!["'foo' is not a return type, expected void, int, string, char, bool, float"]
+        );
+    }
+
+    #[test]
+    fn reassignment() {
+        assert_eq!(error_codes("fun f() { i <- i + 1; }"), Vec::<&str>::new());
+        assert_eq!(error_codes("fun f() { i; }"), vec!["E0101"]);
+    }
+}
@@ -0,0 +1,242 @@
+use crate::builder_dir::backend::{
+    imm, label, Backend, Condition, Memory, Reg, WordSize, DIL, EAX, EDI, ESI, RAX, RCX, RDI, RDX,
+    RSI, XMM0,
+};
+use crate::parser::VariableType;
+
+// libc functions the prelude and user programs link against
+pub const EXTERN_FUNCTIONS: [&str; 4] = ["printf", "malloc", "memcpy", "memcmp"];
+
+// how the checker sees the extern and prelude functions: (name, parameters, variadic, return type)
+pub const BUILTIN_SIGNATURES: [(&str, &[VariableType], bool, VariableType); 11] = [
+    ("printf", &[VariableType::String], true, VariableType::Integer),
+    ("put_i", &[VariableType::Integer], false, VariableType::Void),
+    ("put_f", &[VariableType::FloatingPoint], false, VariableType::Void),
+    ("to_float", &[VariableType::Integer], false, VariableType::FloatingPoint),
+    ("to_int", &[VariableType::FloatingPoint], false, VariableType::Integer),
+    ("put_s", &[VariableType::String], false, VariableType::Void),
+    ("len", &[VariableType::String], false, VariableType::Integer),
+    ("str_eq", &[VariableType::String, VariableType::String], false, VariableType::Boolean),
+    ("concat", &[VariableType::String, VariableType::String], false, VariableType::String),
+    ("ord", &[VariableType::Char], false, VariableType::Integer),
+    ("chr", &[VariableType::Integer], false, VariableType::Char),
+];
+
+// a string is a pointer to its zero terminated bytes, with the length in the qword right
+// before them, so it can go straight to printf and still knows its length
+fn length(string: Reg) -> Memory {
+    Memory {
+        size: WordSize::Qword,
+        base: string,
+        offset: -8,
+    }
+}
+
+fn put_i<B: Backend>(builder: &mut B) {
+    builder.new_string_literal("put_i_fmt_str", "%d\n");
+
+    builder.open_function("put_i");
+    builder.mov(ESI, EDI);
+    builder.mov(EAX, imm(0));
+    builder.call_function("printf", vec![label("put_i_fmt_str")]);
+    builder.close_function();
+}
+
+fn put_f<B: Backend>(builder: &mut B) {
+    builder.new_string_literal("put_f_fmt_str", "%f\n");
+
+    builder.open_function("put_f");
+    // the float is already in xmm0, the one vector register printf has to look at
+    builder.mov(EAX, imm(1));
+    builder.call_function("printf", vec![label("put_f_fmt_str")]);
+    builder.close_function();
+}
+fn to_float<B: Backend>(builder: &mut B) {
+    builder.open_function("to_float");
+    builder.cvtsi2sd(XMM0, EDI);
+    builder.close_function();
+}
+// truncates towards zero, a float outside the int range gives -2147483648
+fn to_int<B: Backend>(builder: &mut B) {
+    builder.open_function("to_int");
+    builder.cvttsd2si(EAX, XMM0);
+    builder.close_function();
+}
+
+// the code of a char
+fn ord<B: Backend>(builder: &mut B) {
+    builder.open_function("ord");
+    builder.movzx(EAX, DIL);
+    builder.close_function();
+}
+// the char with the low byte of the int as its code
+fn chr<B: Backend>(builder: &mut B) {
+    builder.open_function("chr");
+    builder.mov(EAX, EDI);
+    builder.close_function();
+}
+fn put_s<B: Backend>(builder: &mut B) {
+    builder.new_string_literal("put_s_fmt_str", "%s\n");
+
+    builder.open_function("put_s");
+    builder.mov(RSI, RDI);
+    builder.mov(EAX, imm(0));
+    builder.call_function("printf", vec![label("put_s_fmt_str")]);
+    builder.close_function();
+}
+fn len<B: Backend>(builder: &mut B) {
+    builder.open_function("len");
+    let length = Memory {
+        size: WordSize::Dword,
+        ..length(RDI)
+    };
+    builder.mov(EAX, length);
+    builder.close_function();
+}
+fn str_eq<B: Backend>(builder: &mut B) {
+    builder.open_function("str_eq");
+    let different = builder.new_label("str_eq_different");
+    let done = builder.new_label("str_eq_done");
+    builder.mov(RAX, length(RDI));
+    builder.cmp(RAX, length(RSI));
+    builder.jump_if(Condition::NotEqual, &different);
+    // both strings are still in rdi and rsi for memcmp
+    builder.mov(RDX, RAX);
+    builder.call("memcmp");
+    builder.cmp(EAX, imm(0));
+    builder.set(Condition::Equal, RAX.with_size(WordSize::Byte));
+    builder.jmp(&done);
+    builder.label(&different);
+    builder.mov(EAX, imm(0));
+    builder.label(&done);
+    builder.close_function();
+}
+// copies both strings into a new allocation, which is never freed
+fn concat<B: Backend>(builder: &mut B) {
+    builder.open_function("concat");
+    let [left, right, result] = [(); 3].map(|_| {
+        let local = builder.new_local(WordSize::Qword);
+        builder.local_operand(local)
+    });
+    builder.mov(left.clone(), RDI);
+    builder.mov(right.clone(), RSI);
+    // the length, the bytes and the zero terminator
+    builder.mov(RDI, length(RDI));
+    builder.add(RDI, length(RSI));
+    builder.add(RDI, imm(9));
+    builder.call("malloc");
+    builder.mov(RCX, left.clone());
+    builder.mov(RCX, length(RCX));
+    builder.mov(RSI, right.clone());
+    builder.add(RCX, length(RSI));
+    builder.mov(Memory { size: WordSize::Qword, base: RAX, offset: 0 }, RCX);
+    builder.add(RAX, imm(8));
+    builder.mov(result.clone(), RAX);
+
+    // memcpy returns where it copied to, the right string goes after the left one
+    builder.mov(RDI, RAX);
+    builder.mov(RSI, left.clone());
+    builder.mov(RDX, length(RSI));
+    builder.call("memcpy");
+    builder.mov(RSI, left);
+    builder.add(RAX, length(RSI));
+    builder.mov(RDI, RAX);
+    builder.mov(RSI, right.clone());
+    builder.mov(RDX, length(RSI));
+    builder.call("memcpy");
+    builder.mov(RSI, right);
+    builder.add(RAX, length(RSI));
+    builder.mov(Memory { size: WordSize::Byte, base: RAX, offset: 0 }, imm(0));
+    builder.mov(RAX, result);
+    builder.close_function();
+}
+
+pub fn add_prelude<B: Backend>(builder: &mut B) {
+    for function in EXTERN_FUNCTIONS {
+        builder.extern_add(function);
+    }
+    put_i(builder);
+    put_f(builder);
+    to_float(builder);
+    to_int(builder);
+    put_s(builder);
+    len(builder);
+    str_eq(builder);
+    concat(builder);
+    ord(builder);
+    chr(builder);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder_dir::backend::{Instr, Opcode, Operand, TextItem};
+    use crate::builder_dir::builder_gas::Builder;
+
+    // the instructions of one prelude function
+    fn body(name: &str) -> Vec<Instr> {
+        let mut builder = Builder::new_program("main");
+        add_prelude(&mut builder);
+        builder
+            .state()
+            .text_items
+            .iter()
+            .find_map(|item| match item {
+                TextItem::Function(function) if function.name == name => Some(function.body.clone()),
+                _ => None,
+            })
+            .expect("no such function")
+    }
+    fn op(opcode: Opcode, operands: Vec<Operand>) -> Instr {
+        Instr::Op(opcode, operands)
+    }
+    fn at(base: Reg, offset: i32, size: WordSize) -> Operand {
+        Memory { size, base, offset }.into()
+    }
+
+    #[test]
+    fn literals_are_preceded_by_their_length() {
+        let mut builder = Builder::new_program("main");
+        builder.new_string_literal("greeting", "héllo");
+        let data: Vec<&str> = builder.state().data.lines().map(str::trim).collect();
+        let literal = data.iter().position(|line| line.starts_with("greeting:")).unwrap();
+        // the length counts bytes, not chars
+        assert_eq!(data[literal - 1], ".quad 6");
+    }
+
+    #[test]
+    fn len_reads_the_length() {
+        let len = body("len");
+        assert!(len.contains(&op(Opcode::Mov, vec![EAX.into(), at(RDI, -8, WordSize::Dword)])));
+    }
+
+    #[test]
+    fn str_eq_compares_lengths_before_bytes() {
+        let str_eq = body("str_eq");
+        let call = str_eq.iter().position(|instr| *instr == Instr::Call("memcmp".to_string())).unwrap();
+        assert_eq!(str_eq[call - 4..call - 1], [
+            op(Opcode::Mov, vec![RAX.into(), at(RDI, -8, WordSize::Qword)]),
+            op(Opcode::Cmp, vec![RAX.into(), at(RSI, -8, WordSize::Qword)]),
+            Instr::Jump(Some(Condition::NotEqual), ".Lstr_eq_different_1".to_string()),
+        ]);
+        // memcmp gets the shared length as its count
+        assert_eq!(str_eq[call - 1], op(Opcode::Mov, vec![RDX.into(), RAX.into()]));
+        assert_eq!(str_eq[call + 2], op(Opcode::Set(Condition::Equal), vec![RAX.with_size(WordSize::Byte).into()]));
+    }
+
+    #[test]
+    fn concat_builds_a_new_string() {
+        let concat = body("concat");
+        let malloc = concat.iter().position(|instr| *instr == Instr::Call("malloc".to_string())).unwrap();
+        // room for the length, both strings and the zero terminator
+        assert_eq!(concat[malloc - 1], op(Opcode::Add, vec![RDI.into(), imm(9)]));
+        let header = [
+            op(Opcode::Mov, vec![at(RAX, 0, WordSize::Qword), RCX.into()]),
+            op(Opcode::Add, vec![RAX.into(), imm(8)]),
+        ];
+        assert!(concat.windows(2).any(|window| window == header));
+        let copies = concat.iter().filter(|instr| **instr == Instr::Call("memcpy".to_string())).count();
+        assert_eq!(copies, 2);
+        assert!(concat.contains(&op(Opcode::Mov, vec![at(RAX, 0, WordSize::Byte), imm(0)])));
+    }
+}
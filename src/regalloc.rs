@@ -0,0 +1,357 @@
+use crate::builder_dir::backend::Register;
+use crate::ir::{Inst, IrFunction, Value};
+use crate::parser::VariableType;
+use std::collections::HashMap;
+
+// the registers values are kept in, the argument registers, rax and rdx stay free for
+// calls, return values and division
+pub const CALLER_SAVED: [Register; 2] = [Register::R10, Register::R11];
+pub const CALLEE_SAVED: [Register; 5] = [
+    Register::Rbx,
+    Register::R12,
+    Register::R13,
+    Register::R14,
+    Register::R15,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Location {
+    Register(Register),
+    // spilled to a stack slot
+    Stack,
+}
+
+pub struct Allocation {
+    // where every virtual register of the function lives
+    pub locations: Vec<Location>,
+    // callee saved registers the function uses and has to restore for its caller
+    pub callee_saved: Vec<Register>,
+    // caller saved registers holding a value across a call, by (block, instruction) of the call
+    pub call_saves: HashMap<(usize, usize), Vec<Register>>,
+}
+
+// linear scan over the instructions in layout order, every virtual register gets one
+// interval from its definition to its last use
+pub struct RegisterAllocator {
+    // (first, last) instruction position of every virtual register, None when it is never used
+    intervals: Vec<Option<(usize, usize)>>,
+    // position and (block, instruction) of every call
+    calls: Vec<(usize, (usize, usize))>,
+}
+
+impl RegisterAllocator {
+    fn new(function: &IrFunction) -> Self {
+        let mut allocator = Self {
+            intervals: vec![None; function.vregs.len()],
+            calls: vec![],
+        };
+        let mut position = 0;
+        let mut block_starts = vec![];
+        let mut jumps = vec![];
+        for (block_id, block) in function.blocks.iter().enumerate() {
+            block_starts.push(position);
+            for (index, inst) in block.insts.iter().enumerate() {
+                allocator.touch_values(inst.uses(), position);
+                if let Some(dest) = inst.dest() {
+                    allocator.touch(dest.0, position);
+                }
+                if let Inst::Call { .. } = inst {
+                    allocator.calls.push((position, (block_id, index)));
+                }
+                position += 1;
+            }
+            allocator.touch_values(block.terminator.uses(), position);
+            for target in block.terminator.targets() {
+                jumps.push((target, position));
+            }
+            position += 1;
+        }
+        // floats stay in their stack slots, only general purpose registers are handed out
+        for (interval, var_type) in allocator.intervals.iter_mut().zip(&function.vregs) {
+            if *var_type == VariableType::FloatingPoint {
+                *interval = None;
+            }
+        }
+        // a value live at the start of a loop has to survive until the jump back to it
+        let loops: Vec<(usize, usize)> = jumps
+            .into_iter()
+            .map(|(target, position)| (block_starts[target.0], position))
+            .filter(|(start, position)| start <= position)
+            .collect();
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for (loop_start, loop_end) in &loops {
+                for (start, end) in allocator.intervals.iter_mut().flatten() {
+                    if *start < *loop_start && *end >= *loop_start && *end < *loop_end {
+                        *end = *loop_end;
+                        changed = true;
+                    }
+                }
+            }
+        }
+        allocator
+    }
+    fn touch(&mut self, vreg: usize, position: usize) {
+        self.intervals[vreg] = Some(match self.intervals[vreg] {
+            Some((start, end)) => (start.min(position), end.max(position)),
+            None => (position, position),
+        });
+    }
+    fn touch_values(&mut self, values: Vec<&Value>, position: usize) {
+        for value in values {
+            if let Value::VReg(vreg) = value {
+                self.touch(vreg.0, position);
+            }
+        }
+    }
+    fn crosses_call(&self, (start, end): (usize, usize)) -> bool {
+        self.calls
+            .iter()
+            .any(|(position, _)| start < *position && *position < end)
+    }
+
+    fn allocate(&self) -> Allocation {
+        let mut locations = vec![Location::Stack; self.intervals.len()];
+        let mut order: Vec<usize> = (0..self.intervals.len())
+            .filter(|vreg| self.intervals[*vreg].is_some())
+            .collect();
+        order.sort_by_key(|vreg| self.intervals[*vreg].unwrap().0);
+
+        let mut free: Vec<Register> = CALLER_SAVED.iter().chain(&CALLEE_SAVED).copied().collect();
+        // (virtual register, register) of every interval that has not ended yet
+        let mut active: Vec<(usize, Register)> = vec![];
+        for vreg in order {
+            let interval = self.intervals[vreg].unwrap();
+            active.retain(|(other, register)| {
+                let ended = self.intervals[*other].unwrap().1 < interval.0;
+                if ended {
+                    free.push(*register);
+                }
+                !ended
+            });
+            // values that live across a call go where the call leaves them alone
+            let preferred: Vec<Register> = if self.crosses_call(interval) {
+                CALLEE_SAVED.iter().chain(&CALLER_SAVED).copied().collect()
+            } else {
+                CALLER_SAVED.iter().chain(&CALLEE_SAVED).copied().collect()
+            };
+            if let Some(register) = preferred.into_iter().find(|register| free.contains(register)) {
+                free.retain(|other| *other != register);
+                active.push((vreg, register));
+                locations[vreg] = Location::Register(register);
+                continue;
+            }
+            // out of registers, the interval that ends last goes to the stack
+            let (index, &(furthest, register)) = active
+                .iter()
+                .enumerate()
+                .max_by_key(|(_, (other, _))| self.intervals[*other].unwrap().1)
+                .unwrap();
+            if self.intervals[furthest].unwrap().1 > interval.1 {
+                locations[furthest] = Location::Stack;
+                locations[vreg] = Location::Register(register);
+                active[index] = (vreg, register);
+            }
+        }
+
+        let callee_saved = CALLEE_SAVED
+            .into_iter()
+            .filter(|register| locations.contains(&Location::Register(*register)))
+            .collect();
+        let mut call_saves = HashMap::new();
+        for (position, call) in &self.calls {
+            let saved = (0..locations.len())
+                .filter_map(|vreg| match (locations[vreg], self.intervals[vreg]) {
+                    (Location::Register(register), Some((start, end)))
+                        if CALLER_SAVED.contains(&register) && start < *position && *position < end =>
+                    {
+                        Some(register)
+                    }
+                    _ => None,
+                })
+                .collect();
+            call_saves.insert(*call, saved);
+        }
+        Allocation {
+            locations,
+            callee_saved,
+            call_saves,
+        }
+    }
+    pub fn allocate_function(function: &IrFunction) -> Allocation {
+        RegisterAllocator::new(function).allocate()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::{BinOp, Block, BlockId, CmpOp, LocalId, Terminator, VReg};
+
+    fn function(vregs: Vec<VariableType>, blocks: Vec<Block>) -> IrFunction {
+        let mut function = IrFunction::new("f", VariableType::Void);
+        function.vregs = vregs;
+        function.blocks = blocks;
+        function
+    }
+    fn ints(count: usize) -> Vec<VariableType> {
+        vec![VariableType::Integer; count]
+    }
+    fn vreg(index: usize) -> Value {
+        Value::VReg(VReg(index))
+    }
+    fn add(dest: usize, left: Value, right: Value) -> Inst {
+        Inst::Binary {
+            dest: VReg(dest),
+            op: BinOp::Add,
+            left,
+            right,
+        }
+    }
+    fn call() -> Inst {
+        Inst::Call {
+            dest: None,
+            function: "g".to_string(),
+            args: vec![],
+            variadic: false,
+        }
+    }
+    fn block(insts: Vec<Inst>, terminator: Terminator) -> Block {
+        Block { insts, terminator }
+    }
+    // defines %0 to %count-1, then stores them in the given order, so the last one stored
+    // stays live the longest
+    fn pressure(count: usize, order: &[usize]) -> IrFunction {
+        let mut insts: Vec<Inst> = (0..count)
+            .map(|index| add(index, Value::Int(index as i128), Value::Int(1)))
+            .collect();
+        for index in order {
+            insts.push(Inst::Store {
+                local: LocalId(0),
+                value: vreg(*index),
+            });
+        }
+        function(ints(count), vec![block(insts, Terminator::Return(None))])
+    }
+    fn spilled(allocation: &Allocation) -> Vec<usize> {
+        (0..allocation.locations.len())
+            .filter(|vreg| allocation.locations[*vreg] == Location::Stack)
+            .collect()
+    }
+
+    #[test]
+    fn short_intervals_share_caller_saved_registers() {
+        let function = function(
+            ints(3),
+            vec![block(
+                vec![
+                    add(0, Value::Int(1), Value::Int(2)),
+                    add(1, vreg(0), Value::Int(3)),
+                    add(2, vreg(1), Value::Int(4)),
+                ],
+                Terminator::Return(Some(vreg(2))),
+            )],
+        );
+        let allocation = RegisterAllocator::allocate_function(&function);
+        assert_eq!(
+            allocation.locations,
+            vec![
+                Location::Register(Register::R10),
+                Location::Register(Register::R11),
+                // %0 ended before %2 starts
+                Location::Register(Register::R10),
+            ]
+        );
+        assert!(allocation.callee_saved.is_empty());
+    }
+
+    #[test]
+    fn spills_the_new_interval_when_it_ends_last() {
+        let allocation = RegisterAllocator::allocate_function(&pressure(8, &[0, 1, 2, 3, 4, 5, 6, 7]));
+        assert_eq!(spilled(&allocation), vec![7]);
+        assert_eq!(allocation.callee_saved, CALLEE_SAVED.to_vec());
+    }
+
+    #[test]
+    fn spills_the_active_interval_that_ends_last() {
+        let allocation = RegisterAllocator::allocate_function(&pressure(8, &[1, 2, 3, 4, 5, 6, 7, 0]));
+        assert_eq!(spilled(&allocation), vec![0]);
+        // %7 takes over the register of %0
+        assert_eq!(allocation.locations[7], Location::Register(Register::R10));
+    }
+
+    #[test]
+    fn values_live_across_calls_prefer_callee_saved() {
+        let function = function(
+            ints(2),
+            vec![block(
+                vec![add(0, Value::Int(1), Value::Int(2)), call(), add(1, vreg(0), Value::Int(1))],
+                Terminator::Return(Some(vreg(1))),
+            )],
+        );
+        let allocation = RegisterAllocator::allocate_function(&function);
+        assert_eq!(allocation.locations[0], Location::Register(Register::Rbx));
+        assert_eq!(allocation.callee_saved, vec![Register::Rbx]);
+        assert_eq!(allocation.call_saves[&(0, 1)], vec![]);
+    }
+
+    #[test]
+    fn call_saves_caller_saved_registers_live_across_the_call() {
+        let store = |index: usize| Inst::Store {
+            local: LocalId(0),
+            value: vreg(index),
+        };
+        // %6 is only used before the call and %7 only after it, %0 to %5 live across it
+        // but there are only five callee saved registers
+        let mut insts = vec![add(6, Value::Int(0), Value::Int(1)), store(6)];
+        insts.extend((0..6).map(|index| add(index, Value::Int(index as i128), Value::Int(1))));
+        insts.push(call());
+        insts.extend((0..6).map(store));
+        insts.push(add(7, Value::Int(1), Value::Int(1)));
+        insts.push(store(7));
+        let function = function(ints(8), vec![block(insts, Terminator::Return(None))]);
+        let allocation = RegisterAllocator::allocate_function(&function);
+        assert!(spilled(&allocation).is_empty());
+        assert_eq!(allocation.locations[5], Location::Register(Register::R10));
+        assert_eq!(allocation.call_saves, HashMap::from([((0, 8), vec![Register::R10])]));
+    }
+
+    #[test]
+    fn intervals_reaching_into_a_loop_last_until_the_jump_back() {
+        let function = function(
+            ints(2),
+            vec![
+                block(vec![add(0, Value::Int(1), Value::Int(0))], Terminator::Jump(BlockId(1))),
+                block(
+                    vec![add(1, vreg(0), Value::Int(1))],
+                    Terminator::Branch {
+                        op: CmpOp::Less,
+                        left: vreg(1),
+                        right: Value::Int(10),
+                        then_block: BlockId(1),
+                        else_block: BlockId(2),
+                    },
+                ),
+                block(vec![], Terminator::Return(None)),
+            ],
+        );
+        let allocator = RegisterAllocator::new(&function);
+        // %0 is last read at 2 but the branch at 3 can go round again
+        assert_eq!(allocator.intervals[0], Some((0, 3)));
+        assert_eq!(allocator.intervals[1], Some((2, 3)));
+    }
+
+    #[test]
+    fn floats_stay_on_the_stack() {
+        let function = function(
+            vec![VariableType::FloatingPoint],
+            vec![block(
+                vec![add(0, Value::Float(0), Value::Float(1))],
+                Terminator::Return(Some(vreg(0))),
+            )],
+        );
+        let allocation = RegisterAllocator::allocate_function(&function);
+        assert_eq!(allocation.locations, vec![Location::Stack]);
+    }
+}
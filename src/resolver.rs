@@ -0,0 +1,411 @@
+use crate::diagnostic::Diagnostic;
+use crate::parser::{Node, VariableType};
+use crate::prelude::{BUILTIN_SIGNATURES, EXTERN_FUNCTIONS};
+use crate::source_map::Span;
+use std::collections::HashMap;
+
+// index into SymbolTable::symbols
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SymbolId(pub usize);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SymbolKind {
+    Variable {
+        var_type: VariableType,
+    },
+    Parameter {
+        index: usize,
+        var_type: VariableType,
+    },
+    Function {
+        params: Vec<VariableType>,
+        // extra arguments past params are not checked, like printf's
+        variadic: bool,
+        return_type: VariableType,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Symbol {
+    pub name: String,
+    pub kind: SymbolKind,
+    // DUMMY for the prelude and extern functions
+    pub span: Span,
+}
+
+#[derive(Debug, Default)]
+pub struct SymbolTable {
+    symbols: Vec<Symbol>,
+}
+
+impl SymbolTable {
+    fn add(&mut self, name: &str, kind: SymbolKind, span: Span) -> SymbolId {
+        self.symbols.push(Symbol {
+            name: name.to_string(),
+            kind,
+            span,
+        });
+        SymbolId(self.symbols.len() - 1)
+    }
+    pub fn get(&self, id: SymbolId) -> &Symbol {
+        &self.symbols[id.0]
+    }
+    // the type of a variable or parameter
+    pub fn variable_type(&self, id: SymbolId) -> VariableType {
+        match self.get(id).kind {
+            SymbolKind::Variable { var_type } | SymbolKind::Parameter { var_type, .. } => var_type,
+            SymbolKind::Function { .. } => unreachable!("{:?} is a function", self.get(id)),
+        }
+    }
+}
+
+// gives every declaration a symbol and points every use at the symbol it refers to
+pub struct Resolver {
+    table: SymbolTable,
+    functions: HashMap<String, SymbolId>,
+    // innermost block last, the parameters share the function body's scope
+    scopes: Vec<HashMap<String, SymbolId>>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Resolver {
+    pub fn new() -> Self {
+        let mut table = SymbolTable::default();
+        let mut functions = HashMap::new();
+        for (name, params, variadic, return_type) in BUILTIN_SIGNATURES {
+            let kind = SymbolKind::Function {
+                params: params.to_vec(),
+                variadic,
+                return_type,
+            };
+            functions.insert(name.to_string(), table.add(name, kind, Span::DUMMY));
+        }
+        Self {
+            table,
+            functions,
+            scopes: vec![],
+            diagnostics: vec![],
+        }
+    }
+    fn duplicate(&mut self, code: &'static str, message: String, span: Span, previous: SymbolId) {
+        let previous = self.table.get(previous).span;
+        let mut diagnostic = Diagnostic::error(code, message, span);
+        if !previous.is_dummy() {
+            diagnostic = diagnostic.with_label(previous, "first defined here");
+        }
+        self.diagnostics.push(diagnostic);
+    }
+    fn declare(&mut self, name: &str, kind: SymbolKind, span: Span) -> SymbolId {
+        if let Some(&previous) = self.scopes.last().and_then(|scope| scope.get(name)) {
+            self.duplicate(
+                "E0503",
+                format!("variable '{}' is already defined in this scope", name),
+                span,
+                previous,
+            );
+        }
+        let id = self.table.add(name, kind, span);
+        self.scopes.last_mut().unwrap().insert(name.to_string(), id);
+        id
+    }
+    fn lookup(&self, name: &str) -> Option<SymbolId> {
+        self.scopes
+            .iter()
+            .rev()
+            .find_map(|scope| scope.get(name).copied())
+    }
+
+    fn expression(&mut self, node: &mut Node) {
+        match node {
+            Node::Expr { value, .. } => self.expression(value),
+            Node::Const { .. } => {}
+            Node::VariableReference { name, symbol, span } => {
+                *symbol = self.lookup(name);
+                if symbol.is_none() {
+                    self.diagnostics.push(Diagnostic::error(
+                        "E0501",
+                        format!("use of undefined variable '{}'", name),
+                        *span,
+                    ));
+                }
+            }
+            Node::FunctionCall { .. } => self.function_call(node),
+            Node::BinaryOp { left, right, .. } => {
+                self.expression(left);
+                self.expression(right);
+            }
+            Node::UnaryOp { value, .. } => self.expression(value),
+            _ => unreachable!("{:?} is not an expression", node),
+        }
+    }
+    fn function_call(&mut self, node: &mut Node) {
+        let Node::FunctionCall {
+            name,
+            symbol,
+            args,
+            span,
+        } = node
+        else {
+            unreachable!()
+        };
+        for arg in args {
+            self.expression(arg);
+        }
+        *symbol = self.functions.get(name.as_str()).copied();
+        if symbol.is_none() {
+            self.diagnostics.push(Diagnostic::error(
+                "E0502",
+                format!("call to undefined function '{}'", name),
+                *span,
+            ));
+        }
+    }
+    fn block(&mut self, body: &mut [Node]) {
+        self.scopes.push(HashMap::new());
+        for node in body {
+            self.statement(node);
+        }
+        self.scopes.pop();
+    }
+    fn statement(&mut self, node: &mut Node) {
+        match node {
+            Node::Assign {
+                name,
+                symbol,
+                var_type,
+                value,
+                span,
+            } => {
+                // the new variable is not in scope inside its own value
+                self.expression(value);
+                let kind = SymbolKind::Variable {
+                    var_type: *var_type,
+                };
+                *symbol = Some(self.declare(name, kind, *span));
+            }
+            Node::Reassign {
+                name,
+                symbol,
+                value,
+                span,
+            } => {
+                self.expression(value);
+                *symbol = self.lookup(name);
+                if symbol.is_none() {
+                    self.diagnostics.push(Diagnostic::error(
+                        "E0501",
+                        format!("assignment to undefined variable '{}'", name),
+                        *span,
+                    ));
+                }
+            }
+            Node::FunctionCall { .. } => self.function_call(node),
+            Node::Return { value, .. } => {
+                if !matches!(**value, Node::Blank) {
+                    self.expression(value);
+                }
+            }
+            Node::If {
+                condition,
+                body,
+                else_body,
+                ..
+            } => {
+                self.expression(condition);
+                self.block(body);
+                self.block(else_body);
+            }
+            Node::While {
+                condition, body, ..
+            } => {
+                self.expression(condition);
+                self.block(body);
+            }
+            Node::Break { .. } | Node::Continue { .. } => {}
+            _ => unreachable!("{:?} is not a statement", node),
+        }
+    }
+    fn function(&mut self, args: &mut [Node], body: &mut [Node]) {
+        self.scopes.push(HashMap::new());
+        for (index, arg) in args.iter_mut().enumerate() {
+            if let Node::Assign {
+                name,
+                symbol,
+                var_type,
+                span,
+                ..
+            } = arg
+            {
+                let kind = SymbolKind::Parameter {
+                    index,
+                    var_type: *var_type,
+                };
+                *symbol = Some(self.declare(name, kind, *span));
+            }
+        }
+        for node in body {
+            self.statement(node);
+        }
+        self.scopes.pop();
+    }
+    pub fn resolve(&mut self, program: &mut Node) -> Result<SymbolTable, Vec<Diagnostic>> {
+        let Node::Program { body, .. } = program else {
+            unreachable!()
+        };
+        // every function is declared up front so calls can refer to ones further down
+        for function in body.iter_mut() {
+            if let Node::Function {
+                name,
+                symbol,
+                return_type,
+                args,
+                span,
+                ..
+            } = function
+            {
+                if let Some(&previous) = self.functions.get(name.as_str()) {
+                    self.duplicate(
+                        "E0504",
+                        format!("function '{}' is defined more than once", name),
+                        *span,
+                        previous,
+                    );
+                } else if EXTERN_FUNCTIONS.contains(&name.as_str()) {
+                    // the prelude calls these, a definition of our own would replace the libc one
+                    self.diagnostics.push(Diagnostic::error(
+                        "E0504",
+                        format!("function '{}' is already defined by libc", name),
+                        *span,
+                    ));
+                }
+                let params = args
+                    .iter()
+                    .filter_map(|arg| match arg {
+                        Node::Assign { var_type, .. } => Some(*var_type),
+                        _ => None,
+                    })
+                    .collect();
+                let kind = SymbolKind::Function {
+                    params,
+                    variadic: false,
+                    return_type: *return_type,
+                };
+                let id = self.table.add(name, kind, *span);
+                self.functions.entry(name.clone()).or_insert(id);
+                *symbol = Some(id);
+            }
+        }
+        for function in body.iter_mut() {
+            if let Node::Function { args, body, .. } = function {
+                self.function(args, body);
+            }
+        }
+
+        if self.diagnostics.is_empty() {
+            Ok(std::mem::take(&mut self.table))
+        } else {
+            Err(self.diagnostics.clone())
+        }
+    }
+    pub fn resolve_program(program: &mut Node) -> Result<SymbolTable, Vec<Diagnostic>> {
+        Resolver::new().resolve(program)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+
+    fn resolve(source: &str) -> Result<(Node, SymbolTable), Vec<Diagnostic>> {
+        let tokens = Lexer::new()
+            .lex_text(0, source.to_string())
+            .expect("lexing failed");
+        let mut program = Parser::parse_tokens(tokens).expect("parsing failed");
+        let symbols = Resolver::resolve_program(&mut program)?;
+        Ok((program, symbols))
+    }
+    fn error_codes(source: &str) -> Vec<&'static str> {
+        match resolve(source) {
+            Ok(_) => vec![],
+            Err(diagnostics) => diagnostics.iter().map(|diagnostic| diagnostic.code).collect(),
+        }
+    }
+    // the symbols the variables passed to calls resolved to, in source order
+    fn arguments(nodes: &[Node], symbols: &mut Vec<SymbolId>) {
+        for node in nodes {
+            match node {
+                Node::Program { body, .. } | Node::Function { body, .. } => {
+                    arguments(body, symbols)
+                }
+                Node::FunctionCall { args, .. } => arguments(args, symbols),
+                Node::Expr { value, .. } => arguments(std::slice::from_ref(value), symbols),
+                Node::VariableReference { symbol, .. } => symbols.push(symbol.unwrap()),
+                Node::If {
+                    body, else_body, ..
+                } => {
+                    arguments(body, symbols);
+                    arguments(else_body, symbols);
+                }
+                Node::While { body, .. } => arguments(body, symbols),
+                _ => {}
+            }
+        }
+    }
+    fn argument_symbols(source: &str) -> Vec<SymbolId> {
+        let (program, _) = resolve(source).expect("resolving failed");
+        let mut symbols = vec![];
+        arguments(std::slice::from_ref(&program), &mut symbols);
+        symbols
+    }
+
+    #[test]
+    fn nested_scopes() {
+        let visible =
+            "fun f(int a) { int b <- a; if true { int c <- b; while true { put_i(a + b + c); } } }";
+        assert_eq!(error_codes(visible), Vec::<&str>::new());
+        assert_eq!(error_codes("fun f() { if true { int x <- 1; } put_i(x); }"), vec!["E0501"]);
+        assert_eq!(error_codes("fun f() { while true { int x <- 1; } x <- 2; }"), vec!["E0501"]);
+        let else_body = "fun f() { if true {} else { int x <- 1; } put_i(x); }";
+        assert_eq!(error_codes(else_body), vec!["E0501"]);
+        // a variable is not in scope inside its own value
+        assert_eq!(error_codes("fun f() { int x <- x; }"), vec!["E0501"]);
+        // nor in another function
+        assert_eq!(error_codes("fun f() { int x <- 1; } fun g() { put_i(x); }"), vec!["E0501"]);
+    }
+
+    #[test]
+    fn shadowing() {
+        let source = "fun f(int x) { put_i(x); if true { int x <- 2; put_i(x); } put_i(x); }";
+        let symbols = argument_symbols(source);
+        assert_eq!(symbols.len(), 3);
+        assert_ne!(symbols[0], symbols[1]);
+        assert_eq!(symbols[0], symbols[2]);
+        // declaring a name twice in one scope is an error, the parameters share the body's scope
+        assert_eq!(error_codes("fun f() { int x <- 1; int x <- 2; }"), vec!["E0503"]);
+        assert_eq!(error_codes("fun f(int a, int a) {}"), vec!["E0503"]);
+        assert_eq!(error_codes("fun f(int a) { int a <- 1; }"), vec!["E0503"]);
+    }
+
+    #[test]
+    fn functions() {
+        // every function is visible everywhere, whatever order they come in
+        assert_eq!(error_codes("fun main() { g(); } fun g() { main(); }"), Vec::<&str>::new());
+        assert_eq!(error_codes("fun main() { g(); }"), vec!["E0502"]);
+        assert_eq!(error_codes("fun g() {} fun g() {}"), vec!["E0504"]);
+        assert_eq!(error_codes("fun put_i(int a) {}"), vec!["E0504"]);
+        // variables and functions live in different namespaces
+        assert_eq!(error_codes("fun f() { int g <- 1; g(); } fun g() {}"), Vec::<&str>::new());
+    }
+
+    #[test]
+    fn libc_names_are_reserved() {
+        for name in EXTERN_FUNCTIONS {
+            let source = format!("fun {}() {{}}", name);
+            assert_eq!(error_codes(&source), vec!["E0504"], "{}", name);
+        }
+        let diagnostics = resolve("fun malloc(int size) {}").expect_err("resolving passed");
+        assert_eq!(diagnostics[0].message, "function 'malloc' is already defined by libc");
+    }
+}
@@ -0,0 +1,151 @@
+// a byte range `start..end` inside one of the files of a SourceMap
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub file_id: usize,
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    // for diagnostics that do not point anywhere in the source, like a missing file
+    pub const DUMMY: Span = Span {
+        file_id: usize::MAX,
+        start: 0,
+        end: 0,
+    };
+
+    pub fn new(file_id: usize, start: usize, end: usize) -> Self {
+        Self {
+            file_id,
+            start,
+            end,
+        }
+    }
+    pub fn is_dummy(&self) -> bool {
+        self.file_id == Span::DUMMY.file_id
+    }
+    // from the start of self to the end of other
+    pub fn to(self, other: Span) -> Span {
+        if self.is_dummy() {
+            return other;
+        }
+        if other.is_dummy() {
+            return self;
+        }
+        Span::new(self.file_id, self.start, other.end.max(self.end))
+    }
+}
+
+pub struct SourceFile {
+    pub name: String,
+    pub source: String,
+    // byte offset of the first character of every line
+    line_starts: Vec<usize>,
+}
+
+impl SourceFile {
+    // line of the offset, starting at 0
+    fn line_index(&self, offset: usize) -> usize {
+        match self.line_starts.binary_search(&offset) {
+            Ok(line) => line,
+            Err(line) => line - 1,
+        }
+    }
+    // the text of a line without its line break, line starting at 0
+    pub fn line(&self, line: usize) -> &str {
+        let start = self.line_starts[line];
+        let end = self
+            .line_starts
+            .get(line + 1)
+            .copied()
+            .unwrap_or(self.source.len());
+        self.source[start..end].trim_end_matches(['\n', '\r'])
+    }
+    // line and column of the offset, both starting at 1, the column counts characters
+    pub fn location(&self, offset: usize) -> (usize, usize) {
+        let offset = offset.min(self.source.len());
+        let line = self.line_index(offset);
+        let column = self.source[self.line_starts[line]..offset].chars().count() + 1;
+        (line + 1, column)
+    }
+}
+
+#[derive(Default)]
+pub struct SourceMap {
+    files: Vec<SourceFile>,
+}
+
+impl SourceMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn add_file(&mut self, name: &str, source: String) -> usize {
+        let mut line_starts = vec![0];
+        for (offset, char) in source.char_indices() {
+            if char == '\n' {
+                line_starts.push(offset + 1);
+            }
+        }
+        self.files.push(SourceFile {
+            name: name.to_string(),
+            source,
+            line_starts,
+        });
+        self.files.len() - 1
+    }
+    pub fn file(&self, file_id: usize) -> Option<&SourceFile> {
+        self.files.get(file_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(source: &str) -> SourceFile {
+        let mut source_map = SourceMap::new();
+        let file_id = source_map.add_file("test.min", source.to_string());
+        source_map.files.remove(file_id)
+    }
+
+    #[test]
+    fn locations_across_lines() {
+        let file = file("ab\ncd\r\néf");
+        assert_eq!(file.location(0), (1, 1));
+        assert_eq!(file.location(1), (1, 2));
+        assert_eq!(file.location(2), (1, 3));
+        assert_eq!(file.location(3), (2, 1));
+        assert_eq!(file.location(4), (2, 2));
+        assert_eq!(file.location(7), (3, 1));
+        // é takes two bytes but is one column
+        assert_eq!(file.location(9), (3, 2));
+        assert_eq!(file.line(0), "ab");
+        assert_eq!(file.line(1), "cd");
+        assert_eq!(file.line(2), "éf");
+    }
+
+    #[test]
+    fn locations_at_the_end() {
+        let text = file("ab\ncd");
+        assert_eq!(text.location(5), (2, 3));
+        assert_eq!(text.location(100), (2, 3));
+        // after a trailing line break the end is on an empty last line
+        let trailing = file("ab\n");
+        assert_eq!(trailing.location(3), (2, 1));
+        assert_eq!(trailing.line(1), "");
+        let empty = file("");
+        assert_eq!(empty.location(0), (1, 1));
+        assert_eq!(empty.line(0), "");
+    }
+
+    #[test]
+    fn joining_spans() {
+        let first = Span::new(0, 2, 5);
+        let second = Span::new(0, 8, 10);
+        assert_eq!(first.to(second), Span::new(0, 2, 10));
+        // a span inside the first one does not shrink it
+        assert_eq!(first.to(Span::new(0, 3, 4)), first);
+        assert_eq!(Span::DUMMY.to(second), second);
+        assert_eq!(first.to(Span::DUMMY), first);
+    }
+}